@@ -0,0 +1,145 @@
+use log::debug;
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    KeyUsagePurpose,
+};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use time::{Duration, OffsetDateTime};
+use tokio_rustls::rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+use tokio_rustls::rustls::{self, ServerConfig};
+use crate::config;
+
+const CA_CERT_FILE: &str = "ca-cert.pem";
+const CA_KEY_FILE: &str = "ca-key.pem";
+
+/// Paths of the root CA certificate and key in the configuration directory.
+pub fn ca_paths() -> Option<(PathBuf, PathBuf)> {
+    config::get_config_dir().map(|dir| (dir.join(CA_CERT_FILE), dir.join(CA_KEY_FILE)))
+}
+
+/// Generates a new root CA and saves it to the configuration directory.
+/// Returns the path of the certificate to install on devices.
+pub fn init(force: bool) -> Result<PathBuf, String> {
+    let (cert_path, key_path) = ca_paths().ok_or("No configuration directory available")?;
+    if cert_path.exists() && !force {
+        return Err(format!("A CA already exists at {} (use --force to replace it)", cert_path.display()));
+    }
+
+    let mut params = CertificateParams::default();
+    params.distinguished_name.push(DnType::CommonName, "Apimimic Local CA");
+    params.distinguished_name.push(DnType::OrganizationName, "Apimimic");
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.key_usages = vec![
+        KeyUsagePurpose::KeyCertSign,
+        KeyUsagePurpose::CrlSign,
+        KeyUsagePurpose::DigitalSignature,
+    ];
+    let now = OffsetDateTime::now_utc();
+    params.not_before = now - Duration::days(1);
+    params.not_after = now + Duration::days(3650);
+
+    let key = KeyPair::generate().map_err(|e| format!("Failed to generate CA key: {}", e))?;
+    let cert = params.self_signed(&key).map_err(|e| format!("Failed to create CA certificate: {}", e))?;
+
+    fs::write(&cert_path, cert.pem()).map_err(|e| format!("Failed to write {}: {}", cert_path.display(), e))?;
+    write_private(&key_path, key.serialize_pem().as_bytes())
+        .map_err(|e| format!("Failed to write {}: {}", key_path.display(), e))?;
+
+    Ok(cert_path)
+}
+
+/// Writes a file only the current user can read. The file is never readable
+/// by others, not even before its content is written.
+#[cfg(unix)]
+fn write_private(path: &std::path::Path, content: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    // `mode` only applies to new files, a replaced key keeps its old mode.
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all(content)
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &std::path::Path, content: &[u8]) -> std::io::Result<()> {
+    fs::write(path, content)
+}
+
+/// The local root CA, issuing leaf certificates for intercepted hosts.
+pub struct CertificateAuthority {
+    cert: Certificate,
+    key: KeyPair,
+    configs: Mutex<HashMap<String, Arc<ServerConfig>>>,
+}
+
+impl CertificateAuthority {
+    /// Loads the CA created by `apimimic ca init`.
+    pub fn load() -> Result<Self, String> {
+        let (cert_path, key_path) = ca_paths().ok_or("No configuration directory available")?;
+        let cert_pem = fs::read_to_string(&cert_path)
+            .map_err(|e| format!("Failed to read {} (run `apimimic ca init` first): {}", cert_path.display(), e))?;
+        let key_pem = fs::read_to_string(&key_path)
+            .map_err(|e| format!("Failed to read {}: {}", key_path.display(), e))?;
+
+        let key = KeyPair::from_pem(&key_pem).map_err(|e| format!("Invalid CA key: {}", e))?;
+        // Re-signing the parsed parameters yields an issuer with the same
+        // subject and key, which is all that is needed to sign leaves.
+        let cert = CertificateParams::from_ca_cert_pem(&cert_pem)
+            .and_then(|params| params.self_signed(&key))
+            .map_err(|e| format!("Invalid CA certificate: {}", e))?;
+
+        Ok(Self {
+            cert,
+            key,
+            configs: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// TLS server configuration presenting a leaf certificate for `host`.
+    /// Leaves are issued on first use and cached.
+    pub fn server_config(&self, host: &str) -> Result<Arc<ServerConfig>, String> {
+        let host = host.to_lowercase();
+        if let Some(config) = self.configs.lock().unwrap().get(&host) {
+            return Ok(Arc::clone(config));
+        }
+
+        debug!("Issuing certificate for {}", host);
+        let mut params = CertificateParams::new(vec![host.clone()]).map_err(|e| e.to_string())?;
+        params.distinguished_name.push(DnType::CommonName, host.as_str());
+        params.key_usages = vec![KeyUsagePurpose::DigitalSignature, KeyUsagePurpose::KeyEncipherment];
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        // Clients reject leaf certificates valid for more than 398 days.
+        let now = OffsetDateTime::now_utc();
+        params.not_before = now - Duration::days(1);
+        params.not_after = now + Duration::days(365);
+
+        let leaf_key = KeyPair::generate().map_err(|e| e.to_string())?;
+        let leaf = params
+            .signed_by(&leaf_key, &self.cert, &self.key)
+            .map_err(|e| format!("Failed to issue certificate for {}: {}", host, e))?;
+
+        let chain = vec![leaf.der().clone(), self.cert.der().clone()];
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(leaf_key.serialize_der()));
+
+        let mut config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?
+            .with_no_client_auth()
+            .with_single_cert(chain, key)
+            .map_err(|e| e.to_string())?;
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        let config = Arc::new(config);
+        self.configs.lock().unwrap().insert(host, Arc::clone(&config));
+        Ok(config)
+    }
+}
@@ -0,0 +1,192 @@
+use log::{info, warn};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crate::cli::CircuitArgs;
+use crate::metrics::Metrics;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl CircuitState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half_open",
+        }
+    }
+
+    fn gauge(&self) -> i64 {
+        match self {
+            CircuitState::Closed => 0,
+            CircuitState::Open => 1,
+            CircuitState::HalfOpen => 2,
+        }
+    }
+}
+
+struct Inner {
+    state: CircuitState,
+    consecutive_failures: u32,
+    opened_at: Instant,
+    probe_in_flight: bool,
+}
+
+/// Circuit breaker guarding calls to the target server.
+///
+/// After `failure_threshold` consecutive failures the circuit opens and calls
+/// are rejected for `open_duration`. Then a single probe is let through; its
+/// outcome closes the circuit again or re-opens it.
+pub struct CircuitBreaker {
+    name: String,
+    failure_threshold: u32,
+    open_duration: Duration,
+    pub fallback_to_mock: bool,
+    inner: Mutex<Inner>,
+    metrics: Arc<Metrics>,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: &str, args: &CircuitArgs, metrics: Arc<Metrics>) -> Self {
+        let breaker = Self {
+            name: name.to_string(),
+            failure_threshold: args.circuit_failure_threshold,
+            open_duration: Duration::from_millis(args.circuit_open_ms),
+            fallback_to_mock: args.circuit_fallback_mock,
+            inner: Mutex::new(Inner {
+                state: CircuitState::Closed,
+                consecutive_failures: 0,
+                opened_at: Instant::now(),
+                probe_in_flight: false,
+            }),
+            metrics,
+        };
+        breaker.metrics.set(&breaker.series("apimimic_circuit_state"), CircuitState::Closed.gauge());
+        breaker
+    }
+
+    /// Returns a permit if a call may be made right now. The outcome of the
+    /// call is recorded through the permit.
+    pub fn try_acquire(&self) -> Option<Permit<'_>> {
+        if self.failure_threshold == 0 {
+            return Some(Permit { breaker: self, probe: false, recorded: false });
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        let probe = match inner.state {
+            CircuitState::Closed => Some(false),
+            CircuitState::Open if inner.opened_at.elapsed() >= self.open_duration => {
+                self.transition(&mut inner, CircuitState::HalfOpen);
+                inner.probe_in_flight = true;
+                Some(true)
+            }
+            CircuitState::Open => None,
+            CircuitState::HalfOpen if !inner.probe_in_flight => {
+                inner.probe_in_flight = true;
+                Some(true)
+            }
+            CircuitState::HalfOpen => None,
+        };
+
+        if probe.is_none() {
+            self.metrics.incr(&self.series("apimimic_circuit_rejected_total"));
+        }
+        probe.map(|probe| Permit { breaker: self, probe, recorded: false })
+    }
+
+    fn record_success(&self) {
+        if self.failure_threshold == 0 {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures = 0;
+        inner.probe_in_flight = false;
+        if inner.state != CircuitState::Closed {
+            self.transition(&mut inner, CircuitState::Closed);
+        }
+    }
+
+    fn record_failure(&self) {
+        if self.failure_threshold == 0 {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures += 1;
+        inner.probe_in_flight = false;
+        let should_open = match inner.state {
+            CircuitState::Closed => inner.consecutive_failures >= self.failure_threshold,
+            CircuitState::HalfOpen => true,
+            CircuitState::Open => false,
+        };
+        if should_open {
+            inner.opened_at = Instant::now();
+            self.transition(&mut inner, CircuitState::Open);
+        }
+    }
+
+    /// Lets another probe through after one ended without an outcome.
+    fn release_probe(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.state == CircuitState::HalfOpen {
+            inner.probe_in_flight = false;
+        }
+    }
+
+    fn transition(&self, inner: &mut Inner, to: CircuitState) {
+        let from = inner.state;
+        inner.state = to;
+
+        match to {
+            CircuitState::Open => warn!(
+                "Circuit for {} {} -> open after {} consecutive failure(s), retrying in {}ms",
+                self.name, from.as_str(), inner.consecutive_failures, self.open_duration.as_millis()
+            ),
+            _ => info!("Circuit for {} {} -> {}", self.name, from.as_str(), to.as_str()),
+        }
+
+        self.metrics.set(&self.series("apimimic_circuit_state"), to.gauge());
+        self.metrics.incr(&format!(
+            "apimimic_circuit_transitions_total{{target=\"{}\",to=\"{}\"}}",
+            self.name, to.as_str()
+        ));
+    }
+
+    fn series(&self, metric: &str) -> String {
+        format!("{}{{target=\"{}\"}}", metric, self.name)
+    }
+}
+
+/// Permission to make one call through a `CircuitBreaker`. A permit dropped
+/// without an outcome, e.g. because the client went away, releases the
+/// half-open probe so the circuit cannot get stuck.
+pub struct Permit<'a> {
+    breaker: &'a CircuitBreaker,
+    probe: bool,
+    recorded: bool,
+}
+
+impl Permit<'_> {
+    pub fn success(mut self) {
+        self.recorded = true;
+        self.breaker.record_success();
+    }
+
+    pub fn failure(mut self) {
+        self.recorded = true;
+        self.breaker.record_failure();
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if self.probe && !self.recorded {
+            self.breaker.release_probe();
+        }
+    }
+}
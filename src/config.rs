@@ -8,20 +8,27 @@ use std::path::PathBuf;
 pub struct Config {
     pub auth_token: String,
     pub project: String,
+    /// Same as always passing `--no-ping`.
+    #[serde(default)]
+    pub no_ping: bool,
 }
 
-/// Get the configuration file path in a cross-platform way.
-fn get_config_path() -> Option<PathBuf> {
+/// Get the configuration directory in a cross-platform way, creating it if needed.
+pub fn get_config_dir() -> Option<PathBuf> {
     if let Some(proj_dirs) = ProjectDirs::from("com", "apimimic", "apimimic-cli") {
         let config_dir = proj_dirs.config_dir();
         fs::create_dir_all(config_dir).ok()?;
-        let config_file = config_dir.join("config.json");
-        Some(config_file)
+        Some(config_dir.to_path_buf())
     } else {
         None
     }
 }
 
+/// Get the configuration file path in a cross-platform way.
+fn get_config_path() -> Option<PathBuf> {
+    get_config_dir().map(|dir| dir.join("config.json"))
+}
+
 /// Load the configuration from disk.
 pub fn load_config() -> Config {
     if let Some(config_path) = get_config_path() {
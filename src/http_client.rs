@@ -0,0 +1,400 @@
+use bytes::Bytes;
+use log::info;
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+use crate::cli::{PoolArgs, ProxyArgs, RunArgs, TimeoutArgs, TlsArgs};
+
+/// Connect, read and total timeouts of one client. `None` means no limit.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Timeouts {
+    pub connect: Option<Duration>,
+    /// Maximum wait for the response headers, and between two body chunks.
+    pub read: Option<Duration>,
+    pub total: Option<Duration>,
+}
+
+impl Timeouts {
+    fn from_millis(connect: u64, read: u64, total: u64) -> Self {
+        let non_zero = |ms: u64| (ms > 0).then(|| Duration::from_millis(ms));
+        Self {
+            connect: non_zero(connect),
+            read: non_zero(read),
+            total: non_zero(total),
+        }
+    }
+}
+
+/// Error of an outgoing call.
+#[derive(Debug)]
+pub enum CallError {
+    Request(reqwest::Error),
+    ReadTimeout(Duration),
+}
+
+impl CallError {
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            CallError::Request(e) => e.is_timeout(),
+            CallError::ReadTimeout(_) => true,
+        }
+    }
+
+    /// Whether sending the same request again might succeed.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            CallError::Request(e) => e.is_connect() || e.is_timeout() || e.is_request(),
+            CallError::ReadTimeout(_) => true,
+        }
+    }
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CallError::Request(e) => write!(f, "{}", e),
+            CallError::ReadTimeout(after) => write!(f, "read timed out after {}ms", after.as_millis()),
+        }
+    }
+}
+
+/// Sends `request`, enforcing the read timeout while waiting for the
+/// response headers.
+pub async fn send(request: reqwest::RequestBuilder, timeouts: &Timeouts) -> Result<reqwest::Response, CallError> {
+    match timeouts.read {
+        Some(read) => {
+            match tokio::time::timeout(read, request.send()).await {
+                Ok(result) => result.map_err(CallError::Request),
+                Err(_) => Err(CallError::ReadTimeout(read)),
+            }
+        }
+        None => request.send().await.map_err(CallError::Request),
+    }
+}
+
+/// Reads the whole response body, enforcing the read timeout between chunks.
+pub async fn read_body(mut response: reqwest::Response, timeouts: &Timeouts) -> Result<Bytes, CallError> {
+    let read = match timeouts.read {
+        Some(read) => read,
+        None => return response.bytes().await.map_err(CallError::Request),
+    };
+
+    let mut body = Vec::new();
+    loop {
+        match tokio::time::timeout(read, response.chunk()).await {
+            Ok(Ok(Some(chunk))) => body.extend_from_slice(&chunk),
+            Ok(Ok(None)) => return Ok(Bytes::from(body)),
+            Ok(Err(e)) => return Err(CallError::Request(e)),
+            Err(_) => return Err(CallError::ReadTimeout(read)),
+        }
+    }
+}
+
+/// How a client reaches the network.
+#[derive(Clone)]
+pub enum OutboundProxy {
+    /// Use the HTTP_PROXY / HTTPS_PROXY / NO_PROXY environment variables.
+    System,
+    /// Connect directly, ignoring the environment.
+    Direct,
+    /// Use the given proxies for every request not matched by the no-proxy list.
+    Url { proxies: Vec<reqwest::Proxy>, display: String },
+}
+
+/// Proxy environment variables read in `system` mode, by the scheme of the
+/// requests they apply to.
+const PROXY_ENV: &[(&str, &[&str])] = &[
+    ("http", &["HTTP_PROXY", "http_proxy", "ALL_PROXY", "all_proxy"]),
+    ("https", &["HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy"]),
+];
+
+impl OutboundProxy {
+    /// Parses a proxy option value: a URL, `direct` or `system`.
+    pub fn parse(value: Option<&str>, args: &ProxyArgs) -> Result<Self, String> {
+        let value = match value {
+            None => return Self::system(args),
+            Some(v) if v.eq_ignore_ascii_case("system") => return Self::system(args),
+            Some(v) if v.eq_ignore_ascii_case("direct") => return Ok(OutboundProxy::Direct),
+            Some(v) => v,
+        };
+
+        let display = display_url(value)?;
+        let proxy = reqwest::Proxy::all(value).map_err(|e| format!("Invalid proxy URL {}: {}", value, e))?;
+        Ok(OutboundProxy::Url { proxies: vec![configure(proxy, args)], display })
+    }
+
+    /// Uses the proxies from the environment. Without `--no-proxy` or
+    /// `--outbound-proxy-auth` reqwest reads the environment itself,
+    /// otherwise the proxies are built here so the options can be attached.
+    fn system(args: &ProxyArgs) -> Result<Self, String> {
+        if args.no_proxy.is_none() && args.outbound_proxy_auth.is_none() {
+            return Ok(OutboundProxy::System);
+        }
+
+        let mut proxies = Vec::new();
+        let mut display = Vec::new();
+        for (scheme, vars) in PROXY_ENV {
+            let Some(value) = vars.iter().find_map(|var| std::env::var(var).ok().filter(|v| !v.is_empty())) else {
+                continue;
+            };
+            let proxy = match *scheme {
+                "http" => reqwest::Proxy::http(&value),
+                _ => reqwest::Proxy::https(&value),
+            };
+            let proxy = proxy.map_err(|e| format!("Invalid proxy URL {}: {}", value, e))?;
+            display.push(format!("{} via {}", scheme, display_url(&value)?));
+            proxies.push(configure(proxy, args));
+        }
+
+        if proxies.is_empty() {
+            return Err("--no-proxy and --outbound-proxy-auth need a proxy: set --outbound-proxy or HTTP_PROXY/HTTPS_PROXY".to_string());
+        }
+        Ok(OutboundProxy::Url { proxies, display: format!("system ({})", display.join(", ")) })
+    }
+}
+
+/// A proxy URL without credentials, for logging.
+fn display_url(value: &str) -> Result<String, String> {
+    let parsed = url::Url::parse(value).map_err(|e| format!("Invalid proxy URL {}: {}", value, e))?;
+    Ok(format!(
+        "{}://{}{}",
+        parsed.scheme(),
+        parsed.host_str().unwrap_or_default(),
+        parsed.port().map(|p| format!(":{}", p)).unwrap_or_default()
+    ))
+}
+
+/// Attaches the `--outbound-proxy-auth` credentials and the no-proxy list.
+fn configure(mut proxy: reqwest::Proxy, args: &ProxyArgs) -> reqwest::Proxy {
+    if let Some(auth) = &args.outbound_proxy_auth {
+        let (user, password) = auth.split_once(':').unwrap_or((auth, ""));
+        proxy = proxy.basic_auth(user, password);
+    }
+    let no_proxy = match &args.no_proxy {
+        Some(list) => reqwest::NoProxy::from_string(list),
+        None => reqwest::NoProxy::from_env(),
+    };
+    proxy.no_proxy(no_proxy)
+}
+
+impl fmt::Display for OutboundProxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutboundProxy::System => write!(f, "system (HTTP_PROXY/HTTPS_PROXY environment)"),
+            OutboundProxy::Direct => write!(f, "direct"),
+            OutboundProxy::Url { display, .. } => write!(f, "{}", display),
+        }
+    }
+}
+
+/// Client settings for each kind of outgoing call.
+pub struct ClientConfigs {
+    pub remote: ClientConfig,
+    pub upstream: ClientConfig,
+    pub ping: ClientConfig,
+}
+
+impl ClientConfigs {
+    pub fn from_args(args: &RunArgs) -> Result<Self, String> {
+        let base = ClientConfig::from_args(&args.tls)?.with_pool(&args.pool);
+        let proxy = &args.proxy;
+        let default_proxy = proxy.outbound_proxy.as_deref();
+
+        let for_client = |name: &str, value: Option<&String>, timeouts: Timeouts| -> Result<ClientConfig, String> {
+            let outbound = OutboundProxy::parse(value.map(String::as_str).or(default_proxy), proxy)?;
+            info!("Outbound proxy for {}: {}", name, outbound);
+            let config = base.clone().with_proxy(outbound).with_timeouts(timeouts);
+            config.builder().build().map_err(|e| e.to_string())?;
+            Ok(config)
+        };
+
+        let t: &TimeoutArgs = &args.timeouts;
+        Ok(Self {
+            remote: for_client(
+                "remote",
+                proxy.remote_outbound_proxy.as_ref(),
+                Timeouts::from_millis(t.remote_connect_timeout_ms, t.remote_read_timeout_ms, t.remote_timeout_ms),
+            )?,
+            upstream: for_client(
+                "target server",
+                proxy.server_outbound_proxy.as_ref(),
+                Timeouts::from_millis(t.server_connect_timeout_ms, t.server_read_timeout_ms, t.server_timeout_ms),
+            )?
+            .with_follow_redirects(args.redirects.follow_redirects),
+            ping: for_client(
+                "ping",
+                proxy.ping_outbound_proxy.as_ref(),
+                Timeouts::from_millis(t.ping_connect_timeout_ms, t.ping_read_timeout_ms, t.ping_timeout_ms),
+            )?,
+        })
+    }
+}
+
+/// Settings used to build the reqwest clients for the remote, the target
+/// server and the ping endpoint. Each client is built once at startup and
+/// shared, so connections, DNS lookups and TLS sessions are reused.
+#[derive(Clone)]
+pub struct ClientConfig {
+    ca_certs: Vec<reqwest::Certificate>,
+    identity: Option<reqwest::Identity>,
+    insecure: bool,
+    resolve: Option<(String, SocketAddr)>,
+    proxy: OutboundProxy,
+    pool_max_idle_per_host: Option<usize>,
+    pool_idle_timeout: Duration,
+    timeouts: Timeouts,
+    follow_redirects: bool,
+}
+
+impl ClientConfig {
+    /// Loads the certificates and identity referenced by the TLS options.
+    pub fn from_args(args: &TlsArgs) -> Result<Self, String> {
+        let mut ca_certs = Vec::new();
+        for path in &args.ca_cert {
+            let pem = read_file(path)?;
+            let certs = reqwest::Certificate::from_pem_bundle(&pem)
+                .map_err(|e| format!("Invalid CA bundle {}: {}", path.display(), e))?;
+            ca_certs.extend(certs);
+        }
+
+        let identity = match (&args.client_cert, &args.client_key) {
+            (Some(cert), Some(key)) => Some(
+                reqwest::Identity::from_pkcs8_pem(&read_file(cert)?, &read_file(key)?)
+                    .map_err(|e| format!("Invalid client certificate {}: {}", cert.display(), e))?,
+            ),
+            (Some(cert), None) => Some(
+                reqwest::Identity::from_pkcs12_der(
+                    &read_file(cert)?,
+                    args.client_cert_password.as_deref().unwrap_or(""),
+                )
+                .map_err(|e| format!("Invalid PKCS#12 client certificate {}: {}", cert.display(), e))?,
+            ),
+            (None, Some(_)) => return Err("--client-key requires --client-cert".to_string()),
+            (None, None) => None,
+        };
+
+        let config = Self {
+            ca_certs,
+            identity,
+            insecure: args.insecure,
+            resolve: None,
+            proxy: OutboundProxy::System,
+            pool_max_idle_per_host: None,
+            pool_idle_timeout: Duration::from_secs(90),
+            timeouts: Timeouts::default(),
+            follow_redirects: true,
+        };
+
+        // Surface TLS backend errors at startup rather than on the first request.
+        config.builder().build().map_err(|e| e.to_string())?;
+
+        Ok(config)
+    }
+
+    /// Connects to `addr` whenever a request targets `host`, so the URL host
+    /// (and with it SNI and certificate validation) can differ from the
+    /// address actually dialed.
+    pub fn with_resolve(mut self, host: &str, addr: SocketAddr) -> Self {
+        self.resolve = Some((host.to_string(), addr));
+        self
+    }
+
+    pub fn with_proxy(mut self, proxy: OutboundProxy) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    pub fn with_pool(mut self, args: &PoolArgs) -> Self {
+        self.pool_max_idle_per_host = args.pool_max_idle_per_host;
+        self.pool_idle_timeout = Duration::from_millis(args.pool_idle_timeout_ms);
+        self
+    }
+
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    pub fn with_follow_redirects(mut self, follow: bool) -> Self {
+        self.follow_redirects = follow;
+        self
+    }
+
+    pub fn timeouts(&self) -> Timeouts {
+        self.timeouts
+    }
+
+    pub fn builder(&self) -> reqwest::ClientBuilder {
+        let mut builder = reqwest::Client::builder()
+            .pool_idle_timeout(self.pool_idle_timeout);
+
+        if let Some(max_idle) = self.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max_idle);
+        }
+        if let Some(connect) = self.timeouts.connect {
+            builder = builder.connect_timeout(connect);
+        }
+        if let Some(total) = self.timeouts.total {
+            builder = builder.timeout(total);
+        }
+        if !self.follow_redirects {
+            builder = builder.redirect(reqwest::redirect::Policy::none());
+        }
+
+        for cert in &self.ca_certs {
+            builder = builder.add_root_certificate(cert.clone());
+        }
+        if let Some(identity) = &self.identity {
+            builder = builder.identity(identity.clone());
+        }
+        if self.insecure {
+            builder = builder
+                .danger_accept_invalid_certs(true)
+                .danger_accept_invalid_hostnames(true);
+        }
+        if let Some((host, addr)) = &self.resolve {
+            builder = builder.resolve(host, *addr);
+        }
+        match &self.proxy {
+            OutboundProxy::System => {}
+            OutboundProxy::Direct => builder = builder.no_proxy(),
+            OutboundProxy::Url { proxies, .. } => {
+                for proxy in proxies {
+                    builder = builder.proxy(proxy.clone());
+                }
+            }
+        }
+
+        builder
+    }
+
+    pub fn build(&self) -> reqwest::Client {
+        self.builder().build().expect("Failed to build HTTP client")
+    }
+}
+
+/// Rewrites the host of `url` to `server_name` and returns the new URL along
+/// with the socket address the original host resolves to.
+pub async fn override_server_name(url: &str, server_name: &str) -> Result<(String, SocketAddr), String> {
+    let mut parsed = url::Url::parse(url).map_err(|e| format!("Invalid URL {}: {}", url, e))?;
+    let host = parsed.host_str().ok_or_else(|| format!("URL {} has no host", url))?.to_string();
+    let port = parsed.port_or_known_default().unwrap_or(443);
+
+    let addr = tokio::net::lookup_host((host.as_str(), port))
+        .await
+        .map_err(|e| format!("Failed to resolve {}: {}", host, e))?
+        .next()
+        .ok_or_else(|| format!("No address found for {}", host))?;
+
+    parsed
+        .set_host(Some(server_name))
+        .map_err(|e| format!("Invalid server name {}: {}", server_name, e))?;
+
+    Ok((parsed.to_string().trim_end_matches('/').to_string(), addr))
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))
+}
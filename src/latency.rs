@@ -0,0 +1,263 @@
+use rand::Rng;
+use rand_distr::{Distribution, LogNormal, Normal};
+use serde::{Deserialize, Serialize, Serializer};
+use std::fmt;
+use std::time::{Duration, Instant};
+use log::info;
+use tokio::time::sleep;
+use crate::cli::LatencyArgs;
+use crate::pattern::RouteMap;
+
+/// How long a response is delayed. Parsed from a spec such as `150`,
+/// `uniform:50-200`, `normal:120,30`, `lognormal:100,0.5` or
+/// `p50=80,p99=900` (all values in milliseconds).
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "RawProfile")]
+pub enum LatencyProfile {
+    Fixed(u64),
+    Uniform { min: u64, max: u64 },
+    Normal { mean: f64, stddev: f64 },
+    /// Log-normal with the given median and shape, which gives a long tail.
+    LogNormal { median: f64, sigma: f64 },
+    /// Piecewise linear between the given (percentile, ms) points, sorted by
+    /// percentile. Values outside the range are clamped to the nearest point.
+    Percentiles(Vec<(f64, u64)>),
+}
+
+/// Remote and config files may give a plain number of milliseconds or a spec.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawProfile {
+    Millis(u64),
+    Spec(String),
+}
+
+impl TryFrom<RawProfile> for LatencyProfile {
+    type Error = String;
+
+    fn try_from(raw: RawProfile) -> Result<Self, Self::Error> {
+        match raw {
+            RawProfile::Millis(ms) => Ok(Self::Fixed(ms)),
+            RawProfile::Spec(spec) => Self::parse(&spec),
+        }
+    }
+}
+
+fn parse_ms(value: &str) -> Result<f64, String> {
+    let value = value.trim();
+    let number = value.strip_suffix("ms").unwrap_or(value).trim();
+    match number.parse::<f64>() {
+        Ok(ms) if ms.is_finite() && ms >= 0.0 => Ok(ms),
+        _ => Err(format!("Invalid latency {:?}, expected milliseconds", value)),
+    }
+}
+
+fn parse_pair<'a>(value: &'a str, separator: char, spec: &str) -> Result<(&'a str, &'a str), String> {
+    value
+        .split_once(separator)
+        .ok_or_else(|| format!("Invalid latency profile {:?}", spec))
+}
+
+impl LatencyProfile {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let spec = spec.trim();
+
+        if let Some((kind, params)) = spec.split_once(':') {
+            return match kind.trim().to_lowercase().as_str() {
+                "fixed" => Ok(Self::Fixed(parse_ms(params)?.round() as u64)),
+                "uniform" => {
+                    let (min, max) = parse_pair(params, '-', spec)?;
+                    let (min, max) = (parse_ms(min)?.round() as u64, parse_ms(max)?.round() as u64);
+                    if min > max {
+                        return Err(format!("Invalid latency profile {:?}, min is above max", spec));
+                    }
+                    Ok(Self::Uniform { min, max })
+                }
+                "normal" => {
+                    let (mean, stddev) = parse_pair(params, ',', spec)?;
+                    Ok(Self::Normal { mean: parse_ms(mean)?, stddev: parse_ms(stddev)? })
+                }
+                "lognormal" => {
+                    let (median, sigma) = parse_pair(params, ',', spec)?;
+                    let sigma: f64 = sigma.trim().parse()
+                        .ok()
+                        .filter(|s: &f64| s.is_finite() && *s >= 0.0)
+                        .ok_or_else(|| format!("Invalid latency profile {:?}, sigma must be a non-negative number", spec))?;
+                    Ok(Self::LogNormal { median: parse_ms(median)?, sigma })
+                }
+                _ => Err(format!("Unknown latency profile {:?}", kind)),
+            };
+        }
+
+        if spec.starts_with(['p', 'P']) {
+            let mut points = Vec::new();
+            for point in spec.split(',') {
+                let (percentile, ms) = parse_pair(point, '=', spec)?;
+                let percentile: f64 = percentile.trim()
+                    .strip_prefix(['p', 'P'])
+                    .and_then(|p| p.parse().ok())
+                    .filter(|p| (0.0..=100.0).contains(p))
+                    .ok_or_else(|| format!("Invalid percentile {:?} in {:?}", percentile, spec))?;
+                points.push((percentile, parse_ms(ms)?.round() as u64));
+            }
+            points.sort_by(|a, b| a.0.total_cmp(&b.0));
+            return Ok(Self::Percentiles(points));
+        }
+
+        Ok(Self::Fixed(parse_ms(spec)?.round() as u64))
+    }
+
+    /// Draws one delay from the profile.
+    pub fn sample(&self) -> Duration {
+        let mut rng = rand::thread_rng();
+        let ms = match self {
+            Self::Fixed(ms) => *ms as f64,
+            Self::Uniform { min, max } => rng.gen_range(*min..=*max) as f64,
+            Self::Normal { mean, stddev } => Normal::new(*mean, *stddev)
+                .map(|d| d.sample(&mut rng))
+                .unwrap_or(*mean),
+            Self::LogNormal { median, sigma } => LogNormal::new(median.max(f64::MIN_POSITIVE).ln(), *sigma)
+                .map(|d| d.sample(&mut rng))
+                .unwrap_or(*median),
+            Self::Percentiles(points) => interpolate(points, rng.gen_range(0.0..100.0)),
+        };
+        Duration::from_millis(ms.max(0.0).round() as u64)
+    }
+}
+
+fn interpolate(points: &[(f64, u64)], percentile: f64) -> f64 {
+    let (Some(first), Some(last)) = (points.first(), points.last()) else {
+        return 0.0;
+    };
+    if percentile <= first.0 {
+        return first.1 as f64;
+    }
+    for pair in points.windows(2) {
+        let ((p0, v0), (p1, v1)) = (pair[0], pair[1]);
+        if percentile <= p1 {
+            let t = if p1 > p0 { (percentile - p0) / (p1 - p0) } else { 1.0 };
+            return v0 as f64 + t * (v1 as f64 - v0 as f64);
+        }
+    }
+    last.1 as f64
+}
+
+impl fmt::Display for LatencyProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Fixed(ms) => write!(f, "{}ms", ms),
+            Self::Uniform { min, max } => write!(f, "uniform:{}-{}ms", min, max),
+            Self::Normal { mean, stddev } => write!(f, "normal:{},{}ms", mean, stddev),
+            Self::LogNormal { median, sigma } => write!(f, "lognormal:{}ms,{}", median, sigma),
+            Self::Percentiles(points) => {
+                let points: Vec<String> = points.iter().map(|(p, ms)| format!("p{}={}ms", p, ms)).collect();
+                write!(f, "{}", points.join(","))
+            }
+        }
+    }
+}
+
+/// What the simulated latency of an endpoint means.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LatencyMode {
+    /// Wait after the response is in hand, on top of the upstream time.
+    #[default]
+    Added,
+    /// Make the whole response take at least the delay, so upstream time
+    /// counts toward it.
+    Total,
+    /// Wait before calling upstream, delaying the first byte.
+    Ttfb,
+}
+
+impl LatencyMode {
+    pub fn parse(mode: &str) -> Result<Self, String> {
+        match mode.trim().to_lowercase().as_str() {
+            "added" => Ok(Self::Added),
+            "total" => Ok(Self::Total),
+            "ttfb" => Ok(Self::Ttfb),
+            _ => Err(format!("Invalid latency mode {:?}, expected added, total or ttfb", mode)),
+        }
+    }
+}
+
+/// A delay sampled for one request.
+#[derive(Clone, Copy, Debug)]
+pub struct Delay {
+    pub mode: LatencyMode,
+    pub duration: Duration,
+    /// When the request arrived.
+    pub started: Instant,
+}
+
+impl Delay {
+    /// Waits before the remote or target server is called. Safe to call
+    /// more than once per request.
+    pub async fn before_call(&self) {
+        if self.mode == LatencyMode::Ttfb {
+            Self::wait(self.duration.saturating_sub(self.started.elapsed())).await;
+        }
+    }
+
+    /// Waits once the response is in hand.
+    pub async fn after_response(&self) {
+        match self.mode {
+            LatencyMode::Added => Self::wait(self.duration).await,
+            LatencyMode::Total => Self::wait(self.duration.saturating_sub(self.started.elapsed())).await,
+            LatencyMode::Ttfb => {}
+        }
+    }
+
+    async fn wait(duration: Duration) {
+        if !duration.is_zero() {
+            info!("Sleeping for {}ms", duration.as_millis());
+            sleep(duration).await;
+        }
+    }
+}
+
+/// Profiles are written as their spec, which parses back to the same profile.
+impl Serialize for LatencyProfile {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+/// Latency settings from the command line. The profiles take precedence
+/// over the endpoint configuration received from the remote.
+#[derive(Default)]
+pub struct LatencySettings {
+    pub mode: LatencyMode,
+    default: Option<LatencyProfile>,
+    endpoints: RouteMap<LatencyProfile>,
+}
+
+impl LatencySettings {
+    pub fn from_args(args: &LatencyArgs) -> Result<Self, String> {
+        let default = args.latency.as_deref().map(LatencyProfile::parse).transpose()?;
+
+        let mut endpoints = RouteMap::default();
+        for spec in &args.endpoint_latency {
+            let (path, profile) = spec
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid endpoint latency {:?}, expected <path>=<profile>", spec))?;
+            endpoints.insert(path, LatencyProfile::parse(profile)?);
+        }
+
+        Ok(Self {
+            mode: LatencyMode::parse(&args.latency_mode)?,
+            default,
+            endpoints,
+        })
+    }
+
+    /// Profile for a request: the most specific endpoint override, then
+    /// the global override, then the remote configuration.
+    pub fn resolve(&self, method: &str, path: &str, remote: Option<LatencyProfile>) -> Option<LatencyProfile> {
+        self.endpoints
+            .get(method, path)
+            .or(self.default.as_ref())
+            .cloned()
+            .or(remote)
+    }
+}
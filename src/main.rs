@@ -1,11 +1,19 @@
+mod ca;
+mod circuit;
 mod cli;
 mod config;
+mod http_client;
+mod latency;
 mod server;
+mod sse;
+mod metrics;
+mod pattern;
 mod ping;
+mod retry;
 mod utils;
 
 use clap::Parser;
-use cli::{Cli, Commands};
+use cli::{CaCommands, Cli, Commands};
 use env_logger::Env;
 use utils::{parse_listen_address, parse_server_url};
 
@@ -16,12 +24,12 @@ async fn main() {
         .format_timestamp_millis()
         .init();
 
-    let cli = Cli::parse();
+    let mut cli = Cli::parse();
 
     // Load any saved configuration.
     let mut config = config::load_config();
 
-    match &cli.command {
+    match &mut cli.command {
         Some(Commands::SetProject { project }) => {
             config.project = project.clone();
             if let Err(e) = config::save_config(&config) {
@@ -30,10 +38,13 @@ async fn main() {
             }
             println!("Project saved successfully.");
         }
-        Some(Commands::Run { project, listen, remote, server, remote_ping }) => {
-            let project = match project {
-                Some(p) if !p.is_empty() => p,
-                _ if !config.project.is_empty() => &config.project.clone(),
+        Some(Commands::Run(args)) => {
+            args.ping.no_ping |= config.no_ping;
+
+            let project = match &args.project {
+                Some(p) if !p.is_empty() => Some(p.clone()),
+                _ if !config.project.is_empty() => Some(config.project.clone()),
+                _ if !args.vhost.is_empty() => None,
                 _ => {
                     eprintln!("No project provided. Use the -p/--project flag or `set-project` command.");
                     std::process::exit(1);
@@ -41,7 +52,7 @@ async fn main() {
             };
 
             // Parse listen address
-            let listen = match parse_listen_address(listen) {
+            let listen = match parse_listen_address(&args.listen) {
                 Ok(addr) => addr,
                 Err(e) => {
                     eprintln!("Invalid listen address: {}", e);
@@ -50,20 +61,30 @@ async fn main() {
             };
 
             // Parse server URL
-            let server = parse_server_url(server);
+            let server = parse_server_url(&args.server);
 
             server::run_server(
                 &listen,
-                remote.clone(),
-                project.clone(),
-                server.is_some(),
+                project,
                 server,
-                remote_ping.clone(),
+                args,
             ).await;
         }
+        Some(Commands::Ca { command: CaCommands::Init { force } }) => {
+            match ca::init(*force) {
+                Ok(path) => {
+                    println!("CA certificate created at {}", path.display());
+                    println!("Install and trust it on the devices whose HTTPS traffic should be mocked.");
+                }
+                Err(e) => {
+                    eprintln!("Failed to create CA: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
         None => {
             // Default to showing help
-            let _ = Cli::parse_from(&["--help"]);
+            let _ = Cli::parse_from(["--help"]);
         }
     }
 }
@@ -0,0 +1,29 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// In-process counters and gauges, rendered in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    values: Mutex<BTreeMap<String, i64>>,
+}
+
+impl Metrics {
+    /// Adds one to the series `name` (which may include labels).
+    pub fn incr(&self, name: &str) {
+        *self.values.lock().unwrap().entry(name.to_string()).or_insert(0) += 1;
+    }
+
+    /// Sets the series `name` to `value`.
+    pub fn set(&self, name: &str, value: i64) {
+        self.values.lock().unwrap().insert(name.to_string(), value);
+    }
+
+    pub fn render(&self) -> String {
+        self.values
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, value)| format!("{} {}\n", name, value))
+            .collect()
+    }
+}
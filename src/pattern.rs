@@ -0,0 +1,312 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+
+/// One `/`-separated part of a path pattern.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    /// `{name}` or `*`: exactly one segment.
+    Param,
+    /// `**`: any number of segments, including none.
+    Rest,
+}
+
+impl Segment {
+    /// Lower ranks are more specific and take precedence.
+    fn rank(&self) -> u8 {
+        match self {
+            Segment::Literal(_) => 0,
+            Segment::Param => 1,
+            Segment::Rest => 2,
+        }
+    }
+}
+
+/// Strips the query string, fragment and surrounding slashes from a path.
+pub fn normalize(path: &str) -> &str {
+    let end = path.find(['?', '#']).unwrap_or(path.len());
+    path[..end].trim_matches('/')
+}
+
+/// An endpoint path such as `users/me`, `users/{id}` or `files/**`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PathPattern {
+    source: String,
+    segments: Vec<Segment>,
+}
+
+impl fmt::Display for PathPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl PathPattern {
+    pub fn parse(pattern: &str) -> Self {
+        let source = normalize(pattern).to_string();
+        let segments = source
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| match segment {
+                "**" => Segment::Rest,
+                "*" => Segment::Param,
+                _ if segment.starts_with('{') && segment.ends_with('}') => Segment::Param,
+                _ => Segment::Literal(segment.to_string()),
+            })
+            .collect();
+
+        Self { source, segments }
+    }
+
+    /// Whether the last segment is `*`. Rules written before `**` existed
+    /// used a trailing `*` as a prefix.
+    pub fn ends_with_star(&self) -> bool {
+        self.source == "*" || self.source.ends_with("/*")
+    }
+
+    /// Whether `path` (already normalized) matches the pattern.
+    pub fn matches(&self, path: &str) -> bool {
+        let path: Vec<&str> = path.split('/').filter(|segment| !segment.is_empty()).collect();
+        matches_segments(&self.segments, &path)
+    }
+}
+
+fn matches_segments(pattern: &[Segment], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((Segment::Rest, rest)) => (0..=path.len()).any(|skip| matches_segments(rest, &path[skip..])),
+        Some((segment, rest)) => match path.split_first() {
+            Some((first, path_rest)) => {
+                let matched = match segment {
+                    Segment::Literal(literal) => literal == first,
+                    _ => true,
+                };
+                matched && matches_segments(rest, path_rest)
+            }
+            None => false,
+        },
+    }
+}
+
+/// Patterns are ordered by precedence: segments are compared left to right,
+/// with literals before `{param}` and `*` before `**`. Ties are broken
+/// alphabetically so the order never depends on insertion order.
+impl Ord for PathPattern {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let ranks = |pattern: &Self| pattern.segments.iter().map(Segment::rank).collect::<Vec<_>>();
+        ranks(self).cmp(&ranks(other)).then_with(|| self.source.cmp(&other.source))
+    }
+}
+
+impl PartialOrd for PathPattern {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Values keyed by path pattern, looked up by the most specific match.
+#[derive(Debug)]
+pub struct PatternMap<T> {
+    entries: Vec<(PathPattern, T)>,
+}
+
+impl<T> Default for PatternMap<T> {
+    fn default() -> Self {
+        Self { entries: Vec::new() }
+    }
+}
+
+impl<T> PatternMap<T> {
+    /// Adds or replaces the value for `pattern`.
+    pub fn insert(&mut self, pattern: &str, value: T) {
+        let pattern = PathPattern::parse(pattern);
+        match self.entries.binary_search_by(|(existing, _)| existing.cmp(&pattern)) {
+            Ok(index) => self.entries[index].1 = value,
+            Err(index) => self.entries.insert(index, (pattern, value)),
+        }
+    }
+
+    pub fn remove(&mut self, pattern: &str) {
+        let pattern = PathPattern::parse(pattern);
+        if let Ok(index) = self.entries.binary_search_by(|(existing, _)| existing.cmp(&pattern)) {
+            self.entries.remove(index);
+        }
+    }
+
+    /// The value of the most specific pattern matching `path`. The query
+    /// string and surrounding slashes of `path` are ignored.
+    pub fn get(&self, path: &str) -> Option<&T> {
+        let path = normalize(path);
+        self.entries
+            .iter()
+            .find(|(pattern, _)| pattern.matches(path))
+            .map(|(_, value)| value)
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&PathPattern, &T)> {
+        self.entries.iter().map(|(pattern, value)| (pattern, value))
+    }
+
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.entries.iter_mut().map(|(_, value)| value)
+    }
+}
+
+/// Splits an endpoint key such as `GET orders/{id}` into its method and path
+/// pattern. Keys without a method apply to every method.
+pub fn split_method(key: &str) -> (Option<String>, &str) {
+    let key = key.trim();
+    match key.split_once(char::is_whitespace) {
+        Some((method, path)) if !method.is_empty() && method.chars().all(|c| c.is_ascii_alphabetic()) => {
+            (Some(method.to_uppercase()), path.trim())
+        }
+        _ => (None, key),
+    }
+}
+
+/// Values keyed by optional method and path pattern. Entries for the
+/// request's method take precedence over path-only entries.
+#[derive(Debug)]
+pub struct RouteMap<T> {
+    any_method: PatternMap<T>,
+    by_method: HashMap<String, PatternMap<T>>,
+}
+
+impl<T> Default for RouteMap<T> {
+    fn default() -> Self {
+        Self {
+            any_method: PatternMap::default(),
+            by_method: HashMap::new(),
+        }
+    }
+}
+
+impl<T> RouteMap<T> {
+    /// Adds or replaces the value for a key such as `orders/**` or `POST orders`.
+    pub fn insert(&mut self, key: &str, value: T) {
+        match split_method(key) {
+            (Some(method), path) => self.by_method.entry(method).or_default().insert(path, value),
+            (None, path) => self.any_method.insert(path, value),
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        match split_method(key) {
+            (Some(method), path) => {
+                if let Some(patterns) = self.by_method.get_mut(&method) {
+                    patterns.remove(path);
+                }
+            }
+            (None, path) => self.any_method.remove(path),
+        }
+    }
+
+    pub fn get(&self, method: &str, path: &str) -> Option<&T> {
+        self.by_method
+            .get(&method.to_uppercase())
+            .and_then(|patterns| patterns.get(path))
+            .or_else(|| self.any_method.get(path))
+    }
+
+    pub fn clear(&mut self) {
+        self.any_method.clear();
+        self.by_method.clear();
+    }
+
+    /// All entries with their keys, as accepted by `insert`.
+    pub fn iter(&self) -> impl Iterator<Item = (String, &T)> {
+        let any_method = self.any_method.iter().map(|(pattern, value)| (pattern.to_string(), value));
+        let by_method = self.by_method.iter().flat_map(|(method, patterns)| {
+            patterns.iter().map(move |(pattern, value)| (format!("{} {}", method, pattern), value))
+        });
+        any_method.chain(by_method)
+    }
+
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.any_method.values_mut().chain(self.by_method.values_mut().flat_map(PatternMap::values_mut))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literals_beat_params_beat_rest() {
+        let mut patterns = [
+            PathPattern::parse("users/**"),
+            PathPattern::parse("users/{id}"),
+            PathPattern::parse("users/me"),
+        ];
+        patterns.sort();
+        let sorted: Vec<String> = patterns.iter().map(PathPattern::to_string).collect();
+        assert_eq!(sorted, ["users/me", "users/{id}", "users/**"]);
+
+        let mut map = PatternMap::default();
+        map.insert("users/**", "rest");
+        map.insert("users/me", "me");
+        map.insert("users/{id}", "id");
+        assert_eq!(map.get("users/me"), Some(&"me"));
+        assert_eq!(map.get("users/42"), Some(&"id"));
+        assert_eq!(map.get("users/42/orders"), Some(&"rest"));
+    }
+
+    #[test]
+    fn precedence_is_left_to_right() {
+        let mut map = PatternMap::default();
+        map.insert("{kind}/orders", "param first");
+        map.insert("users/{id}", "literal first");
+        assert_eq!(map.get("users/orders"), Some(&"literal first"));
+    }
+
+    #[test]
+    fn query_and_fragment_are_ignored() {
+        assert_eq!(normalize("/users/42?expand=1"), "users/42");
+        assert_eq!(normalize("users/42/#top"), "users/42");
+        assert_eq!(normalize("/?a=b"), "");
+
+        let mut map = PatternMap::default();
+        map.insert("/users/{id}/", 1);
+        assert_eq!(map.get("/users/42?expand=1#top"), Some(&1));
+    }
+
+    #[test]
+    fn single_wildcard_matches_one_segment() {
+        let pattern = PathPattern::parse("orders/*");
+        assert!(pattern.matches("orders/42"));
+        assert!(!pattern.matches("orders"));
+        assert!(!pattern.matches("orders/42/items"));
+        assert!(pattern.ends_with_star());
+        assert!(!PathPattern::parse("orders/{id}").ends_with_star());
+    }
+
+    #[test]
+    fn rest_matches_zero_or_more_segments() {
+        let pattern = PathPattern::parse("files/**");
+        assert!(pattern.matches("files"));
+        assert!(pattern.matches("files/a"));
+        assert!(pattern.matches("files/a/b/c"));
+        assert!(!pattern.matches("other/a"));
+
+        let pattern = PathPattern::parse("**/edit");
+        assert!(pattern.matches("edit"));
+        assert!(pattern.matches("users/1/edit"));
+        assert!(!pattern.matches("users/1"));
+
+        assert!(PathPattern::parse("**").matches(""));
+    }
+
+    #[test]
+    fn method_entries_take_precedence() {
+        let mut map = RouteMap::default();
+        map.insert("orders/**", "any");
+        map.insert("get orders/{id}", "get");
+        assert_eq!(map.get("GET", "orders/1"), Some(&"get"));
+        assert_eq!(map.get("POST", "orders/1"), Some(&"any"));
+    }
+}
@@ -1,9 +1,18 @@
-use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::sync::RwLock;
-use tokio::time::{interval, Duration};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{Notify, RwLock};
+use tokio::time::{sleep, Duration, Instant};
 use serde::{Deserialize, Serialize};
-use log::{info, error, debug};
+use log::{info, error, debug, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+use crate::cli::PingArgs;
+use crate::config;
+use crate::http_client::{self, ClientConfig, Timeouts};
+use crate::latency::LatencyProfile;
+use crate::pattern::RouteMap;
+use crate::sse;
 
 #[derive(Debug, Serialize)]
 struct PingRequest {
@@ -15,106 +24,703 @@ struct PingRequest {
     remote_ping: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
 struct EndpointConfig {
     time: u64,
+    /// Latency profile replacing the fixed `time`, if the remote sends one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    latency: Option<LatencyProfile>,
     proxied: bool,
+    /// Restricts the config to one method, like a `GET orders` key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    method: Option<String>,
+}
+
+impl EndpointConfig {
+    /// Key in the endpoint map, with the method prefixed if there is one.
+    fn key(&self, path: String) -> String {
+        match &self.method {
+            Some(method) => format!("{} {}", method, path),
+            None => path,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
 struct PingResponse {
     message: String,
     endpoints: HashMap<String, EndpointConfig>,
+    /// Server-sent events URL pushing endpoint updates, if the remote has one.
+    #[serde(default)]
+    stream: Option<String>,
+}
+
+/// Data of `update` events on the endpoint stream. `snapshot` events carry
+/// the complete endpoint map instead and have no `removed` keys.
+#[derive(Debug, Deserialize)]
+struct EndpointUpdate {
+    #[serde(default)]
+    endpoints: HashMap<String, EndpointConfig>,
+    #[serde(default)]
+    removed: Vec<String>,
+}
+
+/// Endpoint configuration from the `--endpoints` file. Only the fields
+/// that are set replace the remote configuration of the endpoint.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LocalEndpoint {
+    time: Option<u64>,
+    latency: Option<LatencyProfile>,
+    proxied: Option<bool>,
+    method: Option<String>,
+}
+
+impl LocalEndpoint {
+    /// Merges this entry over the remote configuration, if any.
+    fn apply(&self, remote: Option<(LatencyProfile, bool)>) -> (LatencyProfile, bool) {
+        let (remote_latency, remote_proxied) = match remote {
+            Some((latency, proxied)) => (Some(latency), Some(proxied)),
+            None => (None, None),
+        };
+        let latency = self.latency.clone()
+            .or(self.time.map(LatencyProfile::Fixed))
+            .or(remote_latency)
+            .unwrap_or(LatencyProfile::Fixed(0));
+        (latency, self.proxied.or(remote_proxied).unwrap_or(false))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LocalFile {
+    #[serde(default)]
+    endpoints: BTreeMap<String, LocalEndpoint>,
 }
 
+/// Endpoint configuration from a local file, taking precedence over the
+/// configuration fetched from the remote.
+#[derive(Default)]
+pub struct LocalEndpoints {
+    endpoints: RouteMap<LocalEndpoint>,
+    len: usize,
+}
+
+impl LocalEndpoints {
+    /// Reads an `--endpoints` file, in JSON or YAML.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let file: LocalFile = serde_yaml::from_str(&content)
+            .map_err(|e| format!("Invalid endpoints file {}: {}", path.display(), e))?;
+
+        let mut endpoints = RouteMap::default();
+        let len = file.endpoints.len();
+        for (path, endpoint) in file.endpoints {
+            let key = match &endpoint.method {
+                Some(method) => format!("{} {}", method, path),
+                None => path,
+            };
+            endpoints.insert(&key, endpoint);
+        }
+
+        info!("Loaded {} local endpoint(s) from {}", len, path.display());
+        Ok(Self { endpoints, len })
+    }
+}
+
+/// Endpoint configuration saved to the config directory after every change,
+/// so the next start can route requests before the remote is reachable.
+#[derive(Deserialize, Serialize)]
+struct Snapshot {
+    /// Unix time of the save, in seconds.
+    saved_at: u64,
+    endpoints: BTreeMap<String, EndpointConfig>,
+}
+
+/// Longest silence on the endpoint stream before it is reconnected. The
+/// remote is expected to send keep-alive comments more often.
+const STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
 pub struct EndpointInfo {
-    pub timeout: u64,
+    pub latency: LatencyProfile,
     pub proxied: bool,
     pub timestamp: std::time::Instant,
+    /// Loaded from a snapshot. Provisional configuration does not expire,
+    /// but is replaced as soon as the remote answers.
+    pub provisional: bool,
+}
+
+/// How endpoint configuration is fetched and how long it stays valid.
+#[derive(Clone, Debug)]
+pub struct PingSettings {
+    pub interval: Duration,
+    pub ttl: Duration,
+    /// Keep serving the last known configuration after `ttl` instead of
+    /// dropping it.
+    pub keep_stale: bool,
+    /// Server-sent events URL overriding the one advertised by the remote.
+    pub stream_url: Option<String>,
+    /// Longest wait between pings while the remote is unreachable.
+    pub max_backoff: Duration,
+    pub proxy_when_offline: bool,
+    /// Never contact the remote ping URL or the endpoint stream.
+    pub disabled: bool,
+    /// Where endpoint snapshots are kept, `None` to keep none.
+    pub snapshot_dir: Option<PathBuf>,
+}
+
+impl PingSettings {
+    pub fn from_args(args: &PingArgs) -> Self {
+        Self {
+            interval: Duration::from_millis(args.ping_interval_ms.max(1)),
+            ttl: Duration::from_millis(args.endpoint_ttl_ms),
+            keep_stale: args.keep_stale_endpoints,
+            stream_url: args.endpoint_stream.clone(),
+            max_backoff: Duration::from_millis(args.ping_max_backoff_ms),
+            proxy_when_offline: args.proxy_when_offline,
+            disabled: args.no_ping,
+            snapshot_dir: config::get_config_dir(),
+        }
+    }
+}
+
+/// Outcome of recent pings.
+#[derive(Default)]
+struct PingStatus {
+    last_success: Option<Instant>,
+    /// When the current run of failures started.
+    lost_since: Option<Instant>,
+    failures: u32,
+    last_error: Option<String>,
 }
 
 pub struct EndpointManager {
-    endpoints: Arc<RwLock<HashMap<String, EndpointInfo>>>,
+    endpoints: Arc<RwLock<RouteMap<EndpointInfo>>>,
+    local: Arc<LocalEndpoints>,
+    settings: PingSettings,
+    /// Set while stale configuration is being served, to log it only once.
+    serving_stale: AtomicBool,
+    /// Set while the endpoint stream is connected. Its updates keep the
+    /// configuration current, so polling is paused.
+    streaming: AtomicBool,
+    /// Stream URL advertised in the last ping response.
+    advertised_stream: Mutex<Option<String>>,
+    /// Wakes the stream task when the advertised URL changes.
+    stream_changed: Notify,
+    /// Wakes the ping loop when the endpoint stream disconnects.
+    ping_now: Notify,
+    /// Endpoints of the last saved snapshot, to skip unchanged saves.
+    saved: tokio::sync::Mutex<Option<BTreeMap<String, EndpointConfig>>>,
+    status: Mutex<PingStatus>,
 }
 
 impl EndpointManager {
-    pub fn new() -> Arc<Self> {
+    pub fn new(settings: PingSettings, local: Arc<LocalEndpoints>) -> Arc<Self> {
         Arc::new(Self {
-            endpoints: Arc::new(RwLock::new(HashMap::new())),
+            endpoints: Arc::new(RwLock::new(RouteMap::default())),
+            local,
+            settings,
+            serving_stale: AtomicBool::new(false),
+            streaming: AtomicBool::new(false),
+            advertised_stream: Mutex::new(None),
+            stream_changed: Notify::new(),
+            ping_now: Notify::new(),
+            saved: tokio::sync::Mutex::new(None),
+            status: Mutex::new(PingStatus::default()),
         })
     }
 
-    pub async fn get_endpoint_info(&self, path: &str) -> Option<(u64, bool)> {
+    /// Configuration of the most specific endpoint pattern matching `path`,
+    /// ignoring the query string. Entries for `method` take precedence over
+    /// entries that apply to every method. The fields set by the most
+    /// specific local entry replace those of the remote entry.
+    pub async fn get_endpoint_info(&self, method: &str, path: &str) -> Option<(LatencyProfile, bool)> {
+        let remote = self.get_remote_info(method, path).await;
+        match self.local.endpoints.get(method, path) {
+            Some(local) => Some(local.apply(remote)),
+            None => remote,
+        }
+    }
+
+    async fn get_remote_info(&self, method: &str, path: &str) -> Option<(LatencyProfile, bool)> {
         let endpoints = self.endpoints.read().await;
-        endpoints.get(path).and_then(|info| {
-            if info.timestamp.elapsed().as_secs() > 20 {
-                None
-            } else {
-                Some((info.timeout, info.proxied))
+        endpoints.get(method, path).and_then(|info| {
+            let age = info.timestamp.elapsed();
+            if age <= self.settings.ttl || info.provisional || self.streaming.load(Ordering::Relaxed) {
+                self.serving_stale.store(false, Ordering::Relaxed);
+            } else if !self.settings.keep_stale {
+                return None;
+            } else if !self.serving_stale.swap(true, Ordering::Relaxed) {
+                warn!("Endpoint configuration is {}s old, keeping the last known configuration", age.as_secs());
             }
+            Some((info.latency.clone(), info.proxied))
         })
     }
 
     pub async fn start_ping_service(
-        &self,
+        self: Arc<Self>,
         listen: String,
         remote: String,
         remote_ping: String,
         project: String,
         server: Option<String>,
+        client_config: ClientConfig,
     ) {
-        let endpoints = self.endpoints.clone();
-        
+        self.load_snapshot(&project).await;
+
+        if self.settings.disabled {
+            let ping_request = PingRequest {
+                version: env!("CARGO_PKG_VERSION"),
+                listen,
+                project,
+                server,
+                remote,
+                remote_ping,
+            };
+            info!("Ping disabled, endpoint configuration comes from local files only");
+            debug!(
+                "Would have sent every {}ms to {}: {}",
+                self.settings.interval.as_millis(),
+                ping_request.remote_ping,
+                serde_json::to_string(&ping_request).unwrap_or_default()
+            );
+            return;
+        }
+
+        tokio::spawn(Arc::clone(&self).run_stream(project.clone(), client_config.clone()));
+
         tokio::spawn(async move {
-            let client = reqwest::Client::new();
-            let mut interval = interval(Duration::from_secs(10));
+            let client = client_config.build();
+            let timeouts = client_config.timeouts();
+
+            let ping_request = PingRequest {
+                version: env!("CARGO_PKG_VERSION"),
+                listen,
+                project,
+                server,
+                remote,
+                remote_ping,
+            };
 
             loop {
-                interval.tick().await;
-
-                let ping_request = PingRequest {
-                    version: env!("CARGO_PKG_VERSION"),
-                    listen: listen.clone(),
-                    project: project.clone(),
-                    server: server.clone(),
-                    remote: remote.clone(),
-                    remote_ping: remote_ping.clone(),
+                let delay = if self.streaming.load(Ordering::Relaxed) {
+                    debug!("Endpoint stream connected, skipping ping");
+                    self.settings.interval
+                } else {
+                    match self.ping_once(&client, &timeouts, &ping_request).await {
+                        Ok(()) => self.record_ping_success(&ping_request.remote_ping),
+                        Err(e) => self.record_ping_failure(&ping_request.remote_ping, e),
+                    }
+                };
+
+                tokio::select! {
+                    _ = sleep(delay) => {}
+                    _ = self.ping_now.notified() => debug!("Endpoint stream lost, pinging now"),
+                }
+            }
+        });
+    }
+
+    /// Fetches and applies the endpoint configuration once.
+    async fn ping_once(
+        &self,
+        client: &reqwest::Client,
+        timeouts: &Timeouts,
+        ping_request: &PingRequest,
+    ) -> Result<(), String> {
+        let response = http_client::send(client.post(&ping_request.remote_ping).json(ping_request), timeouts)
+            .await
+            .map_err(|e| format!("Failed to send ping request: {}", e))?;
+
+        let status = response.status();
+        if !status.is_success() {
+            return Err(format!("Ping request failed with status: {}", status));
+        }
+
+        let ping_response = http_client::read_body(response, timeouts).await
+            .map_err(|e| e.to_string())
+            .and_then(|body| serde_json::from_slice::<PingResponse>(&body).map_err(|e| e.to_string()))
+            .map_err(|e| format!("Failed to parse ping response: {}", e))?;
+
+        debug!("Apimimic ping successful {}", status);
+        debug!("Received ping response: {:?}", ping_response.message);
+        {
+            let mut advertised = self.advertised_stream.lock().unwrap();
+            if *advertised != ping_response.stream {
+                *advertised = ping_response.stream;
+                self.stream_changed.notify_one();
+            }
+        }
+
+        let configs = {
+            let mut endpoints = self.endpoints.write().await;
+            endpoints.clear();
+            insert_endpoints(&mut endpoints, ping_response.endpoints, false);
+            snapshot_configs(&endpoints)
+        };
+        self.save_snapshot(&ping_request.project, configs).await;
+        Ok(())
+    }
+
+    /// Records a successful ping and returns the delay until the next one.
+    fn record_ping_success(&self, remote_ping: &str) -> Duration {
+        let mut status = self.status.lock().unwrap();
+        match status.lost_since {
+            Some(since) => info!(
+                "Connectivity to {} restored after {}s ({} failed ping(s))",
+                remote_ping, since.elapsed().as_secs(), status.failures
+            ),
+            None if status.last_success.is_none() => info!("Apimimic ping successful, connected to {}", remote_ping),
+            None => {}
+        }
+
+        status.last_success = Some(Instant::now());
+        status.lost_since = None;
+        status.failures = 0;
+        status.last_error = None;
+        self.settings.interval
+    }
+
+    /// Records a failed ping and returns the backoff until the next one.
+    /// Only the first failure in a row is logged as a warning.
+    fn record_ping_failure(&self, remote_ping: &str, error: String) -> Duration {
+        let mut status = self.status.lock().unwrap();
+        status.failures += 1;
+
+        let delay = self.settings.interval
+            .saturating_mul(2u32.saturating_pow(status.failures.min(16)))
+            .min(self.settings.max_backoff.max(self.settings.interval));
+
+        if status.lost_since.is_none() {
+            status.lost_since = Some(Instant::now());
+            warn!("Connectivity to {} lost: {}", remote_ping, error);
+        }
+        debug!("Ping {} failed ({} in a row), retrying in {}ms: {}", remote_ping, status.failures, delay.as_millis(), error);
+
+        status.last_error = Some(error);
+        delay
+    }
+
+    /// Whether the last ping failed.
+    pub fn is_offline(&self) -> bool {
+        self.status.lock().unwrap().lost_since.is_some()
+    }
+
+    /// Whether requests should skip the remote and go to the target server
+    /// because the remote is unreachable.
+    pub fn offline_fallback(&self) -> bool {
+        self.settings.proxy_when_offline && self.is_offline()
+    }
+
+    /// Connectivity and endpoint configuration state for the status endpoint.
+    pub async fn status(&self) -> serde_json::Value {
+        let endpoints = self.endpoints.read().await.iter().count();
+        let status = self.status.lock().unwrap();
+        let connectivity = if self.settings.disabled {
+            "disabled"
+        } else if status.lost_since.is_some() {
+            "disconnected"
+        } else if status.last_success.is_some() {
+            "connected"
+        } else {
+            "unknown"
+        };
+
+        serde_json::json!({
+            "connectivity": connectivity,
+            "streaming": self.streaming.load(Ordering::Relaxed),
+            "last_success_secs_ago": status.last_success.map(|t| t.elapsed().as_secs()),
+            "disconnected_secs": status.lost_since.map(|t| t.elapsed().as_secs()),
+            "consecutive_failures": status.failures,
+            "last_error": status.last_error,
+            "endpoints": endpoints,
+            "local_endpoints": self.local.len,
+        })
+    }
+
+    /// Keeps the endpoint stream connected while a stream URL is known,
+    /// reconnecting after a ping interval or as soon as a new URL is
+    /// advertised.
+    async fn run_stream(self: Arc<Self>, project: String, client_config: ClientConfig) {
+        // The stream stays open indefinitely, so only the connect and header
+        // timeouts apply.
+        let timeouts = client_config.timeouts();
+        let client = client_config
+            .with_timeouts(Timeouts { total: None, ..timeouts })
+            .build();
+
+        loop {
+            let url = self.settings.stream_url.clone()
+                .or_else(|| self.advertised_stream.lock().unwrap().clone());
+
+            if let Some(url) = url {
+                match self.read_stream(&client, &timeouts, &url, &project).await {
+                    Ok(()) => info!("Endpoint stream {} closed", url),
+                    Err(e) => warn!("Endpoint stream {} failed: {}", url, e),
+                }
+                self.stream_lost().await;
+            }
+
+            tokio::select! {
+                _ = sleep(self.settings.interval) => {}
+                _ = self.stream_changed.notified() => {}
+            }
+        }
+    }
+
+    /// Falls back to pinging after the stream ends. Configuration received
+    /// over the stream was current until now, so its TTL starts over, and
+    /// the ping loop is woken up to fetch the configuration right away.
+    async fn stream_lost(&self) {
+        if !self.streaming.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let now = std::time::Instant::now();
+        for info in self.endpoints.write().await.values_mut() {
+            info.timestamp = now;
+        }
+        self.streaming.store(false, Ordering::Relaxed);
+        self.ping_now.notify_one();
+    }
+
+    /// Applies events from the endpoint stream until it ends.
+    async fn read_stream(
+        &self,
+        client: &reqwest::Client,
+        timeouts: &Timeouts,
+        url: &str,
+        project: &str,
+    ) -> Result<(), String> {
+        let request = client.get(url)
+            .header("Accept", "text/event-stream")
+            .header("apimimic-project-id", project);
+        let mut response = http_client::send(request, timeouts).await.map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("status {}", response.status()));
+        }
+
+        info!("Receiving endpoint updates from {}", url);
+        self.streaming.store(true, Ordering::Relaxed);
+        let mut parser = sse::Parser::default();
+
+        loop {
+            let chunk = match tokio::time::timeout(STREAM_IDLE_TIMEOUT, response.chunk()).await {
+                Ok(Ok(Some(chunk))) => chunk,
+                Ok(Ok(None)) => return Ok(()),
+                Ok(Err(e)) => return Err(e.to_string()),
+                Err(_) => return Err(format!("no data for {}s", STREAM_IDLE_TIMEOUT.as_secs())),
+            };
+
+            for event in parser.push(&chunk) {
+                let update = match serde_json::from_str::<EndpointUpdate>(&event.data) {
+                    Ok(update) => update,
+                    Err(e) => {
+                        error!("Failed to parse {} event: {}", event.name, e);
+                        continue;
+                    }
                 };
 
-                match client.post(&remote_ping)
-                    .json(&ping_request)
-                    .send()
-                    .await {
-                    Ok(response) => {
-                        if response.status().is_success() {
-
-                            let status = response.status();
-
-                            match response.json::<PingResponse>().await {
-                                Ok(ping_response) => {
-                                    info!("Apimimic ping successful {}", status);
-                                    debug!("Received ping response: {:?}", ping_response.message);
-                                    let mut endpoints_write = endpoints.write().await;
-                                    endpoints_write.clear();
-                                    
-                                    let now = std::time::Instant::now();
-                                    for (path, config) in ping_response.endpoints {
-                                        endpoints_write.insert(path, EndpointInfo {
-                                            timeout: config.time,
-                                            proxied: config.proxied,
-                                            timestamp: now,
-                                        });
-                                    }
-                                }
-                                Err(e) => error!("Failed to parse ping response: {}", e),
+                let configs = {
+                    let mut endpoints = self.endpoints.write().await;
+                    match event.name.as_str() {
+                        "snapshot" => {
+                            debug!("Received endpoint snapshot with {} endpoint(s)", update.endpoints.len());
+                            endpoints.clear();
+                        }
+                        "update" => {
+                            debug!("Received endpoint update: {} changed, {} removed", update.endpoints.len(), update.removed.len());
+                            for key in &update.removed {
+                                endpoints.remove(key);
                             }
-                        } else {
-                            error!("Ping request failed with status: {} {}", response.status(), remote_ping);
+                        }
+                        other => {
+                            debug!("Ignoring {} event on endpoint stream", other);
+                            continue;
                         }
                     }
-                    Err(e) => error!("Failed to send ping request: {}", e),
-                }
+                    insert_endpoints(&mut endpoints, update.endpoints, false);
+                    snapshot_configs(&endpoints)
+                };
+                self.save_snapshot(project, configs).await;
             }
+        }
+    }
+
+    /// Loads the snapshot saved by a previous run as provisional
+    /// configuration.
+    async fn load_snapshot(&self, project: &str) {
+        let Some(path) = self.snapshot_path(project) else {
+            return;
+        };
+        let snapshot = match std::fs::read_to_string(&path) {
+            Ok(data) => match serde_json::from_str::<Snapshot>(&data) {
+                Ok(snapshot) => snapshot,
+                Err(e) => {
+                    warn!("Ignoring invalid endpoint snapshot {}: {}", path.display(), e);
+                    return;
+                }
+            },
+            Err(_) => return,
+        };
+
+        let age = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_secs().saturating_sub(snapshot.saved_at))
+            .unwrap_or_default();
+        if self.settings.disabled {
+            info!("Using {} endpoint(s) from {}", snapshot.endpoints.len(), path.display());
+        } else {
+            info!(
+                "Using {} saved endpoint(s) from {}s ago until the remote answers",
+                snapshot.endpoints.len(), age
+            );
+        }
+        insert_endpoints(&mut *self.endpoints.write().await, snapshot.endpoints, true);
+    }
+
+    /// Snapshot file of `project`.
+    fn snapshot_path(&self, project: &str) -> Option<PathBuf> {
+        let name: String = project
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+            .collect();
+        self.settings.snapshot_dir.as_ref().map(|dir| dir.join(format!("endpoints-{}.json", name)))
+    }
+
+    /// Saves `configs` as the snapshot of `project` if they changed. Called
+    /// without holding the endpoint lock, so requests never wait for the disk.
+    async fn save_snapshot(&self, project: &str, configs: BTreeMap<String, EndpointConfig>) {
+        let Some(path) = self.snapshot_path(project) else {
+            return;
+        };
+
+        // Held across the write so concurrent saves cannot interleave.
+        let mut saved = self.saved.lock().await;
+        if saved.as_ref() == Some(&configs) {
+            return;
+        }
+
+        let snapshot = Snapshot {
+            saved_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default(),
+            endpoints: configs,
+        };
+        match tokio::fs::write(&path, serde_json::to_string_pretty(&snapshot).unwrap()).await {
+            Ok(()) => debug!("Saved endpoint snapshot to {}", path.display()),
+            Err(e) => warn!("Failed to save endpoint snapshot {}: {}", path.display(), e),
+        }
+        *saved = Some(snapshot.endpoints);
+    }
+}
+
+/// The snapshot form of `endpoints`.
+fn snapshot_configs(endpoints: &RouteMap<EndpointInfo>) -> BTreeMap<String, EndpointConfig> {
+    endpoints
+        .iter()
+        .map(|(key, info)| {
+            let time = match info.latency {
+                LatencyProfile::Fixed(ms) => ms,
+                _ => 0,
+            };
+            (key, EndpointConfig {
+                time,
+                latency: Some(info.latency.clone()),
+                proxied: info.proxied,
+                method: None,
+            })
+        })
+        .collect()
+}
+
+fn insert_endpoints(
+    endpoints: &mut RouteMap<EndpointInfo>,
+    configs: impl IntoIterator<Item = (String, EndpointConfig)>,
+    provisional: bool,
+) {
+    let now = std::time::Instant::now();
+    for (path, config) in configs {
+        endpoints.insert(&config.key(path), EndpointInfo {
+            latency: config.latency.unwrap_or(LatencyProfile::Fixed(config.time)),
+            proxied: config.proxied,
+            timestamp: now,
+            provisional,
         });
     }
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+    use std::convert::Infallible;
+
+    /// Events as a stand-in remote sends them, split at awkward places.
+    const STREAM: &[&str] = &[
+        ": keep-alive\n\n",
+        "event: snapshot\ndata: {\"endpoints\": {\"orders\": {\"time\": 100, \"proxied\": true},",
+        " \"users/{id}\": {\"time\": 0, \"proxied\": true}}}\n\nevent: upd",
+        "ate\ndata: {\"endpoints\": {\"GET orders\": {\"time\": 0, \"proxied\": false}}, \"removed\": [\"users/{id}\"]}\n\n",
+    ];
+
+    fn settings() -> PingSettings {
+        PingSettings {
+            interval: Duration::from_secs(10),
+            ttl: Duration::from_secs(20),
+            keep_stale: false,
+            stream_url: None,
+            max_backoff: Duration::from_secs(300),
+            proxy_when_offline: false,
+            disabled: false,
+            snapshot_dir: None,
+        }
+    }
+
+    /// Serves `STREAM` to requests carrying the project header and returns
+    /// the stream URL.
+    async fn stand_in() -> String {
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                if req.headers().get("apimimic-project-id").is_none() {
+                    return Ok::<_, Infallible>(Response::builder().status(400).body(Body::empty()).unwrap());
+                }
+                let (mut sender, body) = Body::channel();
+                tokio::spawn(async move {
+                    for chunk in STREAM {
+                        if sender.send_data(chunk.as_bytes().to_vec().into()).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+                Ok(Response::builder()
+                    .header("Content-Type", "text/event-stream")
+                    .body(body)
+                    .unwrap())
+            }))
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let url = format!("http://{}/stream", server.local_addr());
+        tokio::spawn(server);
+        url
+    }
+
+    #[tokio::test]
+    async fn applies_stream_events() {
+        let url = stand_in().await;
+        let manager = EndpointManager::new(settings(), Arc::new(LocalEndpoints::default()));
+        let client = reqwest::Client::builder().no_proxy().build().unwrap();
+
+        manager.read_stream(&client, &Timeouts::default(), &url, "demo").await.unwrap();
+
+        assert_eq!(
+            manager.get_endpoint_info("GET", "orders").await,
+            Some((LatencyProfile::Fixed(0), false))
+        );
+        assert_eq!(
+            manager.get_endpoint_info("POST", "orders?page=2").await,
+            Some((LatencyProfile::Fixed(100), true))
+        );
+        assert_eq!(manager.get_endpoint_info("GET", "users/42").await, None);
+    }
+}
@@ -0,0 +1,107 @@
+use log::{debug, warn};
+use rand::Rng;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+use crate::cli::RetryArgs;
+use crate::http_client::{self, CallError, Timeouts};
+
+/// Methods that can be repeated without changing the result on the target.
+const IDEMPOTENT_METHODS: &[&str] = &["GET", "HEAD", "OPTIONS", "PUT", "DELETE", "TRACE"];
+
+/// Retry settings for outgoing remote and upstream calls.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub retry_on: Vec<u16>,
+    pub retry_non_idempotent: bool,
+    pub budget: Duration,
+}
+
+impl RetryPolicy {
+    pub fn from_args(args: &RetryArgs) -> Self {
+        Self {
+            max_attempts: args.retry_attempts.max(1),
+            base_delay: Duration::from_millis(args.retry_backoff_ms),
+            max_delay: Duration::from_millis(args.retry_max_backoff_ms),
+            retry_on: args.retry_on.clone(),
+            retry_non_idempotent: args.retry_non_idempotent,
+            budget: Duration::from_millis(args.retry_budget_ms),
+        }
+    }
+
+    /// Same policy, but only connection errors are retried. Used for the
+    /// Apimimic remote, whose status codes are the mocked responses.
+    pub fn without_status_retries(mut self) -> Self {
+        self.retry_on.clear();
+        self
+    }
+
+    fn attempts_for(&self, method: &str) -> u32 {
+        let method = method.to_uppercase();
+        if self.retry_non_idempotent || IDEMPOTENT_METHODS.contains(&method.as_str()) {
+            self.max_attempts
+        } else {
+            1
+        }
+    }
+
+    /// Exponential backoff with full jitter for the given (1-based) attempt.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exp = self.base_delay.saturating_mul(2u32.saturating_pow(attempt - 1));
+        let cap = exp.min(self.max_delay).as_millis() as u64;
+        Duration::from_millis(rand::thread_rng().gen_range(0..=cap))
+    }
+}
+
+/// Sends the request produced by `build`, retrying according to `policy`.
+///
+/// `method` is the method of the original client request, which decides
+/// whether the call is considered idempotent.
+pub async fn send<F>(
+    policy: &RetryPolicy,
+    timeouts: &Timeouts,
+    method: &str,
+    target: &str,
+    build: F,
+) -> Result<reqwest::Response, CallError>
+where
+    F: Fn() -> reqwest::RequestBuilder,
+{
+    let started = Instant::now();
+    let max_attempts = policy.attempts_for(method);
+    let mut attempt = 1;
+
+    loop {
+        let result = http_client::send(build(), timeouts).await;
+
+        let retryable = match &result {
+            Ok(resp) => policy.retry_on.contains(&resp.status().as_u16()),
+            Err(e) => e.is_retryable(),
+        };
+        if !retryable || attempt >= max_attempts {
+            return result;
+        }
+
+        let delay = policy.backoff(attempt);
+        if started.elapsed() + delay > policy.budget {
+            debug!("Retry budget exhausted for {} {} after {} attempt(s)", method, target, attempt);
+            return result;
+        }
+
+        match &result {
+            Ok(resp) => warn!(
+                "Attempt {}/{} for {} {} returned {}, retrying in {}ms",
+                attempt, max_attempts, method, target, resp.status(), delay.as_millis()
+            ),
+            Err(e) => warn!(
+                "Attempt {}/{} for {} {} failed: {}, retrying in {}ms",
+                attempt, max_attempts, method, target, e, delay.as_millis()
+            ),
+        }
+
+        sleep(delay).await;
+        attempt += 1;
+    }
+}
@@ -0,0 +1,76 @@
+use hyper::{Body, Method, Response};
+use crate::server::{json_error, ServerState};
+
+/// Path prefix of the local endpoints served by the CLI itself.
+pub const ADMIN_PREFIX: &str = "/__apimimic/";
+
+/// Answers requests to the local admin endpoints, or returns `None` for
+/// anything that should be mocked or proxied.
+pub async fn handle(method: &Method, path: &str, state: &ServerState) -> Option<Response<Body>> {
+    let endpoint = path.strip_prefix(ADMIN_PREFIX)?;
+
+    if endpoint == "chaos" || endpoint.starts_with("chaos/") {
+        return Some(chaos(method, endpoint, state));
+    }
+
+    let response = match endpoint {
+        "status" => Response::builder()
+            .status(200)
+            .header("Content-Type", "application/json")
+            .body(Body::from(status(state).await.to_string())),
+        "metrics" => Response::builder()
+            .status(200)
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .body(Body::from(state.metrics.render())),
+        _ => Response::builder()
+            .status(404)
+            .header("Content-Type", "application/json")
+            .body(Body::from(serde_json::json!({"message": "Apimimic: Unknown admin endpoint"}).to_string())),
+    };
+
+    Some(response.unwrap())
+}
+
+/// `GET chaos` shows the chaos profile, `POST chaos/on|off` switches it and
+/// `POST chaos/rules/<name>/on|off` switches a single rule.
+fn chaos(method: &Method, endpoint: &str, state: &ServerState) -> Response<Body> {
+    let Some(chaos) = &state.chaos else {
+        return json_error(404, "No chaos profile loaded (start with --chaos)");
+    };
+
+    let segments: Vec<&str> = endpoint.split('/').collect();
+    let toggle = match (method, segments.as_slice()) {
+        (&Method::GET, ["chaos"]) => None,
+        (&Method::POST, ["chaos", switch]) => Some((None, *switch)),
+        (&Method::POST, ["chaos", "rules", name, switch]) => Some((Some(*name), *switch)),
+        _ => return json_error(404, "Unknown admin endpoint"),
+    };
+
+    if let Some((name, switch)) = toggle {
+        let enabled = match switch {
+            "on" => true,
+            "off" => false,
+            _ => return json_error(404, "Unknown admin endpoint"),
+        };
+        if !chaos.set_enabled(name, enabled) {
+            return json_error(404, "Unknown chaos rule");
+        }
+    }
+
+    Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json")
+        .body(Body::from(chaos.status().to_string()))
+        .unwrap()
+}
+
+/// Connectivity to the remote ping URL and endpoint configuration state of
+/// every project served, keyed by project ID.
+async fn status(state: &ServerState) -> serde_json::Value {
+    let projects = state.default_project.iter().chain(state.virtual_hosts.iter().map(|(_, project)| project));
+    let mut statuses = serde_json::Map::new();
+    for project in projects {
+        statuses.insert(project.project_id.clone(), project.endpoint_manager.status().await);
+    }
+    serde_json::json!({ "projects": statuses })
+}
@@ -0,0 +1,236 @@
+use hyper::{header, Body, Response};
+use log::{info, warn};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::fmt;
+use std::future::Future;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use crate::pattern::{self, PathPattern};
+use crate::server::json_error;
+
+/// Pause between the first half of a truncated body and the disconnect.
+const TRUNCATE_PAUSE: Duration = Duration::from_millis(100);
+
+/// What a chaos rule does to a matching request.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "fault", rename_all = "snake_case")]
+pub enum Fault {
+    /// Answer with the given status instead of the mock.
+    Status {
+        status: u16,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        body: Option<String>,
+    },
+    /// Close the connection without sending a response.
+    Reset,
+    /// Send the headers and half of the body, then close the connection.
+    Truncate,
+    /// Send a complete response whose JSON body is cut in half.
+    MalformedJson,
+    /// Never answer.
+    Hang,
+}
+
+#[derive(Deserialize)]
+struct ProfileFile {
+    #[serde(default = "enabled_by_default")]
+    enabled: bool,
+    #[serde(default)]
+    rules: Vec<RuleConfig>,
+}
+
+#[derive(Deserialize)]
+struct RuleConfig {
+    name: Option<String>,
+    endpoint: Option<String>,
+    #[serde(default = "always")]
+    probability: f64,
+    #[serde(default = "enabled_by_default")]
+    enabled: bool,
+    #[serde(flatten)]
+    fault: Fault,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+fn always() -> f64 {
+    1.0
+}
+
+struct Rule {
+    name: String,
+    /// `None` applies the rule to every endpoint.
+    endpoint: Option<PathPattern>,
+    probability: f64,
+    fault: Fault,
+    enabled: AtomicBool,
+}
+
+impl Rule {
+    fn matches(&self, path: &str) -> bool {
+        match &self.endpoint {
+            None => true,
+            Some(endpoint) => endpoint.matches(pattern::normalize(path)),
+        }
+    }
+}
+
+/// Chaos rules loaded from a `--chaos` profile. Rules and the profile as a
+/// whole can be switched on and off at runtime.
+pub struct Chaos {
+    enabled: AtomicBool,
+    rules: Vec<Rule>,
+}
+
+impl Chaos {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let profile: ProfileFile = serde_yaml::from_str(&content)
+            .map_err(|e| format!("Invalid chaos profile {}: {}", path.display(), e))?;
+
+        let mut rules = Vec::new();
+        for (index, rule) in profile.rules.into_iter().enumerate() {
+            if !(0.0..=1.0).contains(&rule.probability) {
+                return Err(format!("Invalid probability {} in chaos rule {}", rule.probability, index + 1));
+            }
+            if let Fault::Status { status, .. } = rule.fault {
+                if hyper::StatusCode::from_u16(status).is_err() {
+                    return Err(format!("Invalid status {} in chaos rule {}", status, index + 1));
+                }
+            }
+            let endpoint = rule.endpoint.as_deref().map(PathPattern::parse);
+            if let Some(endpoint) = endpoint.as_ref().filter(|endpoint| endpoint.ends_with_star()) {
+                warn!("Chaos rule {} pattern {} matches a single segment, use ** to match a prefix", index + 1, endpoint);
+            }
+            rules.push(Rule {
+                name: rule.name.unwrap_or_else(|| (index + 1).to_string()),
+                endpoint,
+                probability: rule.probability,
+                fault: rule.fault,
+                enabled: AtomicBool::new(rule.enabled),
+            });
+        }
+
+        info!("Loaded {} chaos rule(s) from {}", rules.len(), path.display());
+        Ok(Self {
+            enabled: AtomicBool::new(profile.enabled),
+            rules,
+        })
+    }
+
+    /// The fault to inject for `path`, if any. The
+    /// first enabled matching rule whose probability roll succeeds wins.
+    pub fn pick(&self, path: &str) -> Option<Fault> {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        let mut rng = rand::thread_rng();
+        self.rules
+            .iter()
+            .filter(|rule| rule.enabled.load(Ordering::Relaxed) && rule.matches(path))
+            .find(|rule| rng.gen_bool(rule.probability))
+            .map(|rule| rule.fault.clone())
+    }
+
+    /// Switches the whole profile, or the rule called `name`, on or off.
+    /// Returns false if there is no such rule.
+    pub fn set_enabled(&self, name: Option<&str>, enabled: bool) -> bool {
+        match name {
+            None => self.enabled.store(enabled, Ordering::Relaxed),
+            Some(name) => match self.rules.iter().find(|rule| rule.name == name) {
+                Some(rule) => rule.enabled.store(enabled, Ordering::Relaxed),
+                None => return false,
+            },
+        }
+        info!("Chaos {} {}", name.unwrap_or("profile"), if enabled { "enabled" } else { "disabled" });
+        true
+    }
+
+    pub fn status(&self) -> serde_json::Value {
+        let rules: Vec<_> = self.rules.iter().map(|rule| serde_json::json!({
+            "name": rule.name,
+            "endpoint": rule.endpoint.as_ref().map(|e| e.to_string()),
+            "probability": rule.probability,
+            "enabled": rule.enabled.load(Ordering::Relaxed),
+            "fault": rule.fault,
+        })).collect();
+
+        serde_json::json!({
+            "enabled": self.enabled.load(Ordering::Relaxed),
+            "rules": rules,
+        })
+    }
+}
+
+/// Error handed to hyper to drop a connection without a response.
+#[derive(Debug)]
+pub struct ConnectionReset;
+
+impl fmt::Display for ConnectionReset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "connection reset by chaos rule")
+    }
+}
+
+impl std::error::Error for ConnectionReset {}
+
+impl From<Infallible> for ConnectionReset {
+    fn from(e: Infallible) -> Self {
+        match e {}
+    }
+}
+
+/// Applies `fault` to a request whose normal response is produced by `next`.
+pub async fn inject<F>(fault: Fault, path: &str, next: F) -> Result<Response<Body>, ConnectionReset>
+where
+    F: Future<Output = Result<Response<Body>, Infallible>>,
+{
+    warn!("Injecting {:?} into {}", fault, path);
+
+    match fault {
+        Fault::Status { status, body } => Ok(match body {
+            Some(body) => Response::builder()
+                .status(status)
+                .body(Body::from(body))
+                .unwrap(),
+            None => json_error(status, "Injected by chaos rule"),
+        }),
+        Fault::Reset => Err(ConnectionReset),
+        Fault::Hang => std::future::pending().await,
+        Fault::Truncate => {
+            let (mut parts, body) = next.await?.into_parts();
+            let body = hyper::body::to_bytes(body).await.unwrap_or_default();
+            // Announce the full length so the client notices the cut.
+            parts.headers.insert(header::CONTENT_LENGTH, body.len().into());
+
+            let (mut sender, truncated) = Body::channel();
+            let half = body.slice(..body.len() / 2);
+            tokio::spawn(async move {
+                let _ = sender.send_data(half).await;
+                // Give the connection time to flush the first half.
+                tokio::time::sleep(TRUNCATE_PAUSE).await;
+                sender.abort();
+            });
+            Ok(Response::from_parts(parts, truncated))
+        }
+        Fault::MalformedJson => {
+            let (mut parts, body) = next.await?.into_parts();
+            let body = hyper::body::to_bytes(body).await.unwrap_or_default();
+            let malformed = if body.len() > 1 {
+                body.slice(..body.len() / 2)
+            } else {
+                bytes::Bytes::from_static(b"{\"")
+            };
+            parts.headers.remove(header::CONTENT_LENGTH);
+            parts.headers.insert(header::CONTENT_TYPE, header::HeaderValue::from_static("application/json"));
+            Ok(Response::from_parts(parts, Body::from(malformed)))
+        }
+    }
+}
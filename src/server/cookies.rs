@@ -0,0 +1,116 @@
+use crate::cli::CookieArgs;
+
+/// Keyword that removes an attribute instead of replacing its value.
+const STRIP: &str = "strip";
+
+/// How an attribute of proxied `Set-Cookie` headers is changed.
+#[derive(Clone, Debug, PartialEq)]
+enum AttributeRule {
+    Keep,
+    Strip,
+    Replace(String),
+}
+
+impl AttributeRule {
+    fn from_option(value: &Option<String>) -> Self {
+        match value {
+            None => AttributeRule::Keep,
+            Some(v) if v.eq_ignore_ascii_case(STRIP) => AttributeRule::Strip,
+            Some(v) => AttributeRule::Replace(v.clone()),
+        }
+    }
+}
+
+/// Rewrites `Set-Cookie` attributes so cookies issued by the target server
+/// are accepted by the browser on the local listener.
+#[derive(Clone, Debug)]
+pub struct CookieRewrite {
+    domain: AttributeRule,
+    path: AttributeRule,
+    same_site: AttributeRule,
+    strip_secure: bool,
+}
+
+impl CookieRewrite {
+    pub fn from_args(args: &CookieArgs) -> Self {
+        Self {
+            domain: AttributeRule::from_option(&args.cookie_domain),
+            path: AttributeRule::from_option(&args.cookie_path),
+            same_site: AttributeRule::from_option(&args.cookie_samesite),
+            strip_secure: args.cookie_strip_secure,
+        }
+    }
+
+    fn is_noop(&self) -> bool {
+        self.domain == AttributeRule::Keep
+            && self.path == AttributeRule::Keep
+            && self.same_site == AttributeRule::Keep
+            && !self.strip_secure
+    }
+
+    /// Applies the rules to one `Set-Cookie` header value. Only attributes
+    /// present in the cookie are rewritten.
+    pub fn apply(&self, cookie: &str) -> String {
+        if self.is_noop() {
+            return cookie.to_string();
+        }
+
+        let mut parts = cookie.split(';').map(str::trim);
+        let mut rewritten = vec![parts.next().unwrap_or_default().to_string()];
+
+        // Browsers reject `SameSite=None` without `Secure`, so once `Secure`
+        // is gone such cookies fall back to `Lax` unless told otherwise.
+        let lax = AttributeRule::Replace("Lax".to_string());
+
+        for attribute in parts {
+            let (name, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+            let rule = match name.to_ascii_lowercase().as_str() {
+                "domain" => &self.domain,
+                "path" => &self.path,
+                "samesite" if self.strip_secure
+                    && self.same_site == AttributeRule::Keep
+                    && value.trim().eq_ignore_ascii_case("none") => &lax,
+                "samesite" => &self.same_site,
+                "secure" if self.strip_secure => continue,
+                _ => &AttributeRule::Keep,
+            };
+
+            match rule {
+                AttributeRule::Keep => rewritten.push(attribute.to_string()),
+                AttributeRule::Strip => {}
+                AttributeRule::Replace(value) => rewritten.push(format!("{}={}", name, value)),
+            }
+        }
+
+        rewritten.join("; ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewrite(strip_secure: bool, same_site: Option<&str>) -> CookieRewrite {
+        CookieRewrite {
+            domain: AttributeRule::Keep,
+            path: AttributeRule::Keep,
+            same_site: AttributeRule::from_option(&same_site.map(str::to_string)),
+            strip_secure,
+        }
+    }
+
+    #[test]
+    fn strip_secure_downgrades_same_site_none() {
+        let cookies = rewrite(true, None);
+        assert_eq!(cookies.apply("s=1; Path=/; Secure; SameSite=None"), "s=1; Path=/; SameSite=Lax");
+        assert_eq!(cookies.apply("s=1; Secure; SameSite=Strict"), "s=1; SameSite=Strict");
+    }
+
+    #[test]
+    fn explicit_same_site_wins() {
+        let cookies = rewrite(true, Some("None"));
+        assert_eq!(cookies.apply("s=1; Secure; SameSite=None"), "s=1; SameSite=None");
+        let cookies = rewrite(false, None);
+        assert_eq!(cookies.apply("s=1; Secure; SameSite=None"), "s=1; Secure; SameSite=None");
+    }
+}
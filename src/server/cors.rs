@@ -0,0 +1,99 @@
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Body, Method, Request, Response};
+use crate::cli::CorsArgs;
+use crate::server::json_error;
+
+/// CORS settings applied to every response when `--cors` is enabled.
+pub struct CorsPolicy {
+    origins: Vec<String>,
+    methods: String,
+    headers: Option<String>,
+    expose_headers: Option<String>,
+    credentials: bool,
+    max_age: u64,
+}
+
+impl CorsPolicy {
+    pub fn from_args(args: &CorsArgs) -> Option<Self> {
+        if !args.cors {
+            return None;
+        }
+
+        Some(Self {
+            origins: args.cors_origin.clone(),
+            methods: args.cors_methods.join(", "),
+            headers: (!args.cors_headers.is_empty()).then(|| args.cors_headers.join(", ")),
+            expose_headers: (!args.cors_expose_headers.is_empty()).then(|| args.cors_expose_headers.join(", ")),
+            credentials: args.cors_credentials,
+            max_age: args.cors_max_age,
+        })
+    }
+
+    /// Value for `Access-Control-Allow-Origin`, or `None` if the origin is not allowed.
+    fn allowed_origin(&self, origin: &str) -> Option<String> {
+        if self.origins.iter().any(|o| o == "*") {
+            // A wildcard is not allowed together with credentials, so reflect the origin instead.
+            return Some(if self.credentials { origin.to_string() } else { "*".to_string() });
+        }
+        self.origins
+            .iter()
+            .any(|o| o.eq_ignore_ascii_case(origin))
+            .then(|| origin.to_string())
+    }
+
+    /// Answers a CORS preflight request locally. Returns `None` for anything
+    /// that is not a preflight.
+    pub fn preflight(&self, req: &Request<Body>) -> Option<Response<Body>> {
+        let headers = req.headers();
+        if req.method() != Method::OPTIONS || !headers.contains_key(header::ACCESS_CONTROL_REQUEST_METHOD) {
+            return None;
+        }
+        let origin = headers.get(header::ORIGIN)?.to_str().ok()?;
+
+        if self.allowed_origin(origin).is_none() {
+            return Some(json_error(403, &format!("CORS origin {} is not allowed", origin)));
+        }
+
+        let mut response = Response::builder()
+            .status(204)
+            .header(header::ACCESS_CONTROL_ALLOW_METHODS, &self.methods)
+            .header(header::ACCESS_CONTROL_MAX_AGE, self.max_age.to_string());
+
+        // Without a configured list, allow whatever headers the browser asks for.
+        let allow_headers = self.headers.clone().or_else(|| {
+            headers
+                .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string())
+        });
+        if let Some(allow_headers) = allow_headers {
+            response = response.header(header::ACCESS_CONTROL_ALLOW_HEADERS, allow_headers);
+        }
+
+        let mut response = response.body(Body::empty()).unwrap();
+        self.apply(Some(origin), response.headers_mut());
+        Some(response)
+    }
+
+    /// Adds the CORS response headers for `origin`, replacing any set by the
+    /// remote or the target server.
+    pub fn apply(&self, origin: Option<&str>, headers: &mut HeaderMap) {
+        let allowed = match origin.and_then(|o| self.allowed_origin(o)) {
+            Some(allowed) => allowed,
+            None => return,
+        };
+
+        if let Ok(value) = HeaderValue::from_str(&allowed) {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, value);
+        }
+        if allowed != "*" {
+            headers.append(header::VARY, HeaderValue::from_static("Origin"));
+        }
+        if self.credentials {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+        }
+        if let Some(expose) = self.expose_headers.as_ref().and_then(|v| HeaderValue::from_str(v).ok()) {
+            headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, expose);
+        }
+    }
+}
@@ -0,0 +1,230 @@
+use hyper::header::{self, HeaderMap, HeaderName};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response};
+use log::{debug, error, info};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsAcceptor;
+use crate::ca::CertificateAuthority;
+use crate::cli::ForwardArgs;
+use crate::http_client::{self, Timeouts};
+use crate::server::{json_error, serve_local, ClientAddr, ServerState};
+
+/// Headers that only apply to a single connection and are not forwarded.
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// What to do with a request received in forward-proxy mode.
+pub enum Dispatch {
+    /// Handle the request like any reverse-proxy request (mock or proxy it).
+    Local(Request<Body>),
+    /// The request was tunneled or passed through to its destination.
+    Done(Response<Body>),
+}
+
+/// Forward-proxy mode: requests to `mock_hosts` are mocked, everything else
+/// is passed through to its destination untouched. With a CA, HTTPS tunnels
+/// to `mock_hosts` are intercepted and mocked as well.
+pub struct ForwardProxy {
+    mock_hosts: Vec<String>,
+    client: reqwest::Client,
+    timeouts: Timeouts,
+    ca: Option<CertificateAuthority>,
+}
+
+impl ForwardProxy {
+    pub fn from_args(args: &ForwardArgs, client: reqwest::Client, timeouts: Timeouts) -> Result<Option<Self>, String> {
+        if !args.forward_proxy {
+            return Ok(None);
+        }
+
+        let ca = if args.mitm { Some(CertificateAuthority::load()?) } else { None };
+
+        Ok(Some(Self {
+            mock_hosts: args.mock_host.iter().map(|h| h.to_lowercase()).collect(),
+            client,
+            timeouts,
+            ca,
+        }))
+    }
+
+    /// Whether requests to `host` should be mocked. `*.example.com` matches
+    /// any subdomain of `example.com`.
+    pub fn is_mocked(&self, host: &str) -> bool {
+        let host = host.to_lowercase();
+        self.mock_hosts.iter().any(|pattern| match pattern.strip_prefix("*.") {
+            Some(domain) => host.ends_with(&format!(".{}", domain)),
+            None => *pattern == host,
+        })
+    }
+
+    pub async fn dispatch(&self, req: Request<Body>, state: Arc<ServerState>) -> Dispatch {
+        if req.method() == Method::CONNECT {
+            let host = req.uri().host().unwrap_or_default().to_string();
+            return match &self.ca {
+                Some(ca) if self.is_mocked(&host) => Dispatch::Done(intercept(req, &host, ca, state)),
+                _ => Dispatch::Done(tunnel(req)),
+            };
+        }
+
+        // Origin-form requests were sent to this server directly rather than
+        // through it, so they are handled like in reverse-proxy mode.
+        let host = match (req.uri().scheme(), req.uri().host()) {
+            (Some(_), Some(host)) => host.to_string(),
+            _ => return Dispatch::Local(req),
+        };
+
+        // Absolute-form requests to mocked hosts are mocked, without the
+        // headers meant for this proxy.
+        if self.is_mocked(&host) {
+            debug!("Mocking forward-proxy request to {}", host);
+            let mut req = req;
+            strip_hop_by_hop(req.headers_mut());
+            return Dispatch::Local(req);
+        }
+
+        Dispatch::Done(self.pass_through(req).await)
+    }
+
+    /// Sends an absolute-form request to its destination and streams the
+    /// response back.
+    async fn pass_through(&self, req: Request<Body>) -> Response<Body> {
+        let (parts, body) = req.into_parts();
+        let url = parts.uri.to_string();
+        info!("Passing through {} {}", parts.method, url);
+
+        let method = reqwest::Method::from_bytes(parts.method.as_str().as_bytes()).unwrap();
+        let mut outgoing = self.client.request(method, &url);
+        for (name, value) in &parts.headers {
+            if !is_hop_by_hop(name) {
+                outgoing = outgoing.header(name.as_str(), value.as_bytes());
+            }
+        }
+
+        match http_client::send(outgoing.body(reqwest::Body::wrap_stream(body)), &self.timeouts).await {
+            Ok(resp) => {
+                let mut response = Response::builder().status(resp.status().as_u16());
+                for (name, value) in resp.headers() {
+                    if !is_hop_by_hop(name) {
+                        response = response.header(name.as_str(), value.as_bytes());
+                    }
+                }
+                response.body(Body::wrap_stream(resp.bytes_stream())).unwrap()
+            }
+            Err(e) if e.is_timeout() => {
+                error!("Timed out passing through to {}: {}", url, e);
+                json_error(504, &format!("Timed out contacting {}: {}", url, e))
+            }
+            Err(e) => {
+                error!("Failed to pass through to {}: {}", url, e);
+                json_error(502, &format!("Failed to contact {}: {}", url, e))
+            }
+        }
+    }
+}
+
+/// Answers a CONNECT request and tunnels the upgraded connection to the
+/// requested authority.
+fn tunnel(req: Request<Body>) -> Response<Body> {
+    let authority = match req.uri().authority() {
+        Some(authority) => authority.to_string(),
+        None => return json_error(400, "CONNECT requires host:port"),
+    };
+
+    info!("Tunneling CONNECT to {}", authority);
+    tokio::spawn(async move {
+        let mut upgraded = match hyper::upgrade::on(req).await {
+            Ok(upgraded) => upgraded,
+            Err(e) => {
+                error!("Failed to upgrade CONNECT to {}: {}", authority, e);
+                return;
+            }
+        };
+        let mut target = match TcpStream::connect(&authority).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("Failed to connect tunnel to {}: {}", authority, e);
+                return;
+            }
+        };
+        if let Err(e) = tokio::io::copy_bidirectional(&mut upgraded, &mut target).await {
+            debug!("Tunnel to {} closed: {}", authority, e);
+        }
+    });
+
+    Response::new(Body::empty())
+}
+
+/// Answers a CONNECT request and terminates TLS on the upgraded connection
+/// with a certificate for `host`, serving the requests inside as mocks.
+fn intercept(req: Request<Body>, host: &str, ca: &CertificateAuthority, state: Arc<ServerState>) -> Response<Body> {
+    let config = match ca.server_config(host) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Failed to intercept {}: {}", host, e);
+            return json_error(502, &format!("Failed to intercept {}: {}", host, e));
+        }
+    };
+
+    info!("Intercepting HTTPS to {}", host);
+    let host = host.to_string();
+    let client = req.extensions().get::<ClientAddr>().copied();
+    tokio::spawn(async move {
+        let upgraded = match hyper::upgrade::on(req).await {
+            Ok(upgraded) => upgraded,
+            Err(e) => {
+                error!("Failed to upgrade CONNECT to {}: {}", host, e);
+                return;
+            }
+        };
+        let tls = match TlsAcceptor::from(config).accept(upgraded).await {
+            Ok(tls) => tls,
+            Err(e) => {
+                error!("TLS handshake for {} failed (is the Apimimic CA trusted?): {}", host, e);
+                return;
+            }
+        };
+
+        let service = service_fn(move |mut req: Request<Body>| {
+            if let Some(client) = client {
+                req.extensions_mut().insert(client);
+            }
+            serve_local(req, Arc::clone(&state))
+        });
+        if let Err(e) = Http::new().http1_only(true).serve_connection(tls, service).await {
+            debug!("Intercepted connection to {} closed: {}", host, e);
+        }
+    });
+
+    Response::new(Body::empty())
+}
+
+fn is_hop_by_hop(name: &HeaderName) -> bool {
+    HOP_BY_HOP_HEADERS.contains(&name.as_str()) || name == header::HOST
+}
+
+/// Removes the hop-by-hop headers, including those named in `Connection`,
+/// but keeps `Host`.
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let listed: Vec<String> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_lowercase())
+        .filter(|name| !name.is_empty() && name != "host")
+        .collect();
+    for name in HOP_BY_HOP_HEADERS.iter().copied().chain(listed.iter().map(String::as_str)) {
+        headers.remove(name);
+    }
+}
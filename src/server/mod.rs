@@ -1,64 +1,356 @@
+pub mod admin;
+pub mod chaos;
+pub mod cookies;
+pub mod cors;
+pub mod forward;
 pub mod request;
-pub mod proxy; 
+pub mod throttle;
+pub mod proxy;
+pub mod ratelimit;
+pub mod vhost;
 
 use log::{info, error};
 use std::net::SocketAddr;
 use std::sync::Arc;
-use hyper::Server;
+use hyper::server::conn::AddrStream;
+use hyper::{Body, Request, Response, Server};
 use hyper::service::{make_service_fn, service_fn};
 use tokio::sync::oneshot;
 use std::convert::Infallible;
-use crate::ping::EndpointManager;
+use crate::circuit::CircuitBreaker;
+use crate::cli::RunArgs;
+use crate::http_client::{self, ClientConfigs, Timeouts};
+use crate::latency::LatencySettings;
+use crate::metrics::Metrics;
+use crate::ping::{EndpointManager, LocalEndpoints, PingSettings};
+use crate::retry::RetryPolicy;
+use crate::server::chaos::{Chaos, ConnectionReset};
+use crate::server::cookies::CookieRewrite;
+use crate::server::cors::CorsPolicy;
+use crate::server::forward::{Dispatch, ForwardProxy};
+use crate::server::proxy::Upstream;
+use crate::server::ratelimit::RateLimiter;
+use crate::server::throttle::Throttle;
+use crate::server::vhost::{Matcher, VirtualHostSpec};
+
+/// An Apimimic project served by this process, with its own remote,
+/// target server and endpoint configuration.
+pub struct Project {
+    pub project_id: String,
+    pub remote_base: String,
+    pub upstream: Option<Upstream>,
+    pub endpoint_manager: Arc<EndpointManager>,
+}
+
+/// Settings shared by every request handled by the server.
+pub struct ServerState {
+    pub default_project: Option<Arc<Project>>,
+    pub virtual_hosts: Vec<(Matcher, Arc<Project>)>,
+    pub remote_retry: RetryPolicy,
+    pub remote_client: reqwest::Client,
+    pub remote_timeouts: Timeouts,
+    pub metrics: Arc<Metrics>,
+    pub cors: Option<CorsPolicy>,
+    pub forward: Option<ForwardProxy>,
+    pub latency: LatencySettings,
+    pub chaos: Option<Chaos>,
+    pub throttle: Throttle,
+    pub rate_limiter: RateLimiter,
+}
+
+/// Address of the client that sent a request, stored in its extensions.
+#[derive(Clone, Copy, Debug)]
+pub struct ClientAddr(pub SocketAddr);
+
+/// Builds an error response with a JSON `message` body.
+pub fn json_error(status: u16, message: &str) -> Response<Body> {
+    let error_json = serde_json::json!({"message": format!("Apimimic: {}", message)}).to_string();
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(error_json))
+        .unwrap()
+}
+
+/// Handles one request, applying the settings that wrap every response.
+async fn serve(req: Request<Body>, state: Arc<ServerState>) -> Result<Response<Body>, ConnectionReset> {
+    let req = match &state.forward {
+        Some(forward) => match forward.dispatch(req, Arc::clone(&state)).await {
+            Dispatch::Local(req) => req,
+            Dispatch::Done(response) => return Ok(response),
+        },
+        None => req,
+    };
+
+    serve_local(req, state).await
+}
+
+/// Handles a request that is mocked or proxied by this server, as opposed
+/// to passed through in forward-proxy mode.
+pub async fn serve_local(req: Request<Body>, state: Arc<ServerState>) -> Result<Response<Body>, ConnectionReset> {
+    let origin = req.headers()
+        .get(hyper::header::ORIGIN)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string());
+
+    if let Some(cors) = &state.cors {
+        if let Some(response) = cors.preflight(&req) {
+            return Ok(response);
+        }
+    }
+
+    // Admin endpoints belong to the CLI itself, so they are answered
+    // whichever project, if any, the request is routed to.
+    if let Some(mut response) = admin::handle(req.method(), req.uri().path(), &state).await {
+        if let Some(cors) = &state.cors {
+            cors.apply(origin.as_deref(), response.headers_mut());
+        }
+        return Ok(response);
+    }
+
+    let (project, mut req) = vhost::route(&state.virtual_hosts, state.default_project.as_ref(), req);
+    let path = req.uri().path().to_string();
+    let fault = state.chaos.as_ref().and_then(|chaos| chaos.pick(path.trim_start_matches('/')));
+
+    let bandwidth = state.throttle.resolve(req.method().as_str(), &path);
+    if let Some(bandwidth) = bandwidth {
+        let body = std::mem::take(req.body_mut());
+        *req.body_mut() = bandwidth.pace(body);
+    }
+
+    let response = async {
+        match project {
+            Some(project) => request::handle(req, Arc::clone(&state), project).await,
+            None => Ok(json_error(404, "No project configured for this host")),
+        }
+    };
+    let mut response = match fault {
+        Some(fault) => chaos::inject(fault, &path, response).await?,
+        None => response.await?,
+    };
+
+    if let Some(bandwidth) = bandwidth {
+        response = response.map(|body| bandwidth.pace(body));
+    }
+
+    if let Some(cors) = &state.cors {
+        cors.apply(origin.as_deref(), response.headers_mut());
+    }
+
+    Ok(response)
+}
+
+/// Creates projects from the shared server settings.
+struct ProjectBuilder<'a> {
+    listen: &'a str,
+    args: &'a RunArgs,
+    clients: &'a ClientConfigs,
+    retry: &'a RetryPolicy,
+    metrics: &'a Arc<Metrics>,
+    local_endpoints: &'a Arc<LocalEndpoints>,
+}
+
+impl ProjectBuilder<'_> {
+    /// Creates a project and starts its ping service. `server_name`
+    /// overrides the TLS server name of the target server.
+    async fn build(
+        &self,
+        project_id: String,
+        remote_base: String,
+        target_server: Option<String>,
+        server_name: Option<&str>,
+    ) -> Arc<Project> {
+        let endpoint_manager = EndpointManager::new(PingSettings::from_args(&self.args.ping), Arc::clone(self.local_endpoints));
+
+        // Start ping service
+        Arc::clone(&endpoint_manager).start_ping_service(
+            self.listen.to_string(),
+            remote_base.clone(),
+            self.args.remote_ping.clone(),
+            project_id.clone(),
+            target_server.clone(),
+            self.clients.ping.clone(),
+        ).await;
+
+        let upstream = match target_server {
+            Some(url) => Some(self.build_upstream(url, server_name).await),
+            None => None,
+        };
+
+        Arc::new(Project {
+            project_id,
+            remote_base,
+            upstream,
+            endpoint_manager,
+        })
+    }
+
+    /// Creates a target server, applying the TLS server name override if one
+    /// is given.
+    async fn build_upstream(&self, url: String, server_name: Option<&str>) -> Upstream {
+        let args = self.args;
+        let mut upstream_client = self.clients.upstream.clone();
+        let mut url = url;
+        if let Some(server_name) = server_name {
+            match http_client::override_server_name(&url, server_name).await {
+                Ok((rewritten, addr)) => {
+                    info!("Connecting to {} as {}", addr, server_name);
+                    upstream_client = upstream_client.with_resolve(server_name, addr);
+                    url = rewritten;
+                }
+                Err(e) => {
+                    eprintln!("Invalid TLS server name: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+
+        Upstream {
+            circuit: CircuitBreaker::new(&url, &args.circuit, Arc::clone(self.metrics)),
+            url,
+            retry: self.retry.clone(),
+            client: upstream_client.build(),
+            timeouts: upstream_client.timeouts(),
+            rewrite_location: args.redirects.rewrite_location,
+            cookies: CookieRewrite::from_args(&args.cookies),
+        }
+    }
+}
 
 /// Starts the HTTP server and handles incoming requests
 pub async fn run_server(
     listen: &str,
-    remote_base: String,
-    project_id: String,
-    proxy_enabled: bool,
+    project_id: Option<String>,
     target_server: Option<String>,
-    remote_ping: String,
+    args: &RunArgs,
 ) {
-    info!("Starting server on {} with project_id: {}", listen, project_id);
-    if proxy_enabled {
-        info!("Proxy mode enabled. Target server: {:?}", target_server);
+    info!("Starting server on {} with project_id: {}", listen, project_id.as_deref().unwrap_or("-"));
+    if let Some(target_server) = &target_server {
+        info!("Proxy mode enabled. Target server: {}", target_server);
     }
 
     let addr: SocketAddr = listen.parse().expect("Invalid address format");
 
-    let remote_base = remote_base.clone();
-    let project_id = project_id.clone();
-    let target_server = target_server.clone();
+    let clients = match ClientConfigs::from_args(args) {
+        Ok(clients) => clients,
+        Err(e) => {
+            eprintln!("Invalid client configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
 
-    let endpoint_manager = EndpointManager::new();
-    
-    // Start ping service
-    Arc::clone(&endpoint_manager).start_ping_service(
-        listen.to_string(),
-        remote_base.clone(),
-        remote_ping,
-        project_id.clone(),
-        target_server.clone(),
-    ).await;
-
-    let endpoint_manager = Arc::clone(&endpoint_manager);
-
-    let make_svc = make_service_fn(move |_conn| {
-        let remote_base = remote_base.clone();
-        let project_id = project_id.clone();
-        let target_server = target_server.clone();
-        let endpoint_manager = Arc::clone(&endpoint_manager);
+    let metrics = Arc::new(Metrics::default());
+
+    let retry = RetryPolicy::from_args(&args.retry);
+
+    let forward = match ForwardProxy::from_args(&args.forward, clients.upstream.build(), clients.upstream.timeouts()) {
+        Ok(forward) => forward,
+        Err(e) => {
+            eprintln!("Invalid forward proxy configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let latency = match LatencySettings::from_args(&args.latency) {
+        Ok(latency) => latency,
+        Err(e) => {
+            eprintln!("Invalid latency configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let rate_limiter = match RateLimiter::from_args(&args.rate_limit) {
+        Ok(rate_limiter) => rate_limiter,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let throttle = match Throttle::from_args(&args.bandwidth) {
+        Ok(throttle) => throttle,
+        Err(e) => {
+            eprintln!("Invalid bandwidth configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let chaos = match &args.chaos.chaos {
+        Some(path) => match Chaos::load(path) {
+            Ok(chaos) => Some(chaos),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let local_endpoints = match &args.ping.endpoints {
+        Some(path) => match LocalEndpoints::load(path) {
+            Ok(local_endpoints) => local_endpoints,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        None => LocalEndpoints::default(),
+    };
+    let local_endpoints = Arc::new(local_endpoints);
+
+    let projects = ProjectBuilder {
+        listen,
+        args,
+        clients: &clients,
+        retry: &retry,
+        metrics: &metrics,
+        local_endpoints: &local_endpoints,
+    };
+
+    let default_project = match project_id {
+        Some(project_id) => Some(projects.build(project_id, args.remote.clone(), target_server, args.tls.tls_server_name.as_deref()).await),
+        None => None,
+    };
+
+    let mut virtual_hosts = Vec::new();
+    for spec in &args.vhost {
+        let spec = match VirtualHostSpec::parse(spec) {
+            Ok(spec) => spec,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        };
+        info!("Serving {:?} with project_id: {}", spec.matcher, spec.project);
+
+        let remote_base = spec.remote.unwrap_or_else(|| args.remote.clone());
+        let project = projects.build(spec.project, remote_base, spec.server, None).await;
+        virtual_hosts.push((spec.matcher, project));
+    }
+
+    let state = Arc::new(ServerState {
+        default_project,
+        virtual_hosts,
+        remote_retry: retry.without_status_retries(),
+        remote_client: clients.remote.build(),
+        remote_timeouts: clients.remote.timeouts(),
+        metrics,
+        cors: CorsPolicy::from_args(&args.cors),
+        forward,
+        latency,
+        chaos,
+        throttle,
+        rate_limiter,
+    });
+
+    let make_svc = make_service_fn(move |conn: &AddrStream| {
+        let state = Arc::clone(&state);
+        let client = ClientAddr(conn.remote_addr());
 
         async move {
-            Ok::<_, Infallible>(service_fn(move |req| {
-                request::handle(
-                    req,
-                    remote_base.clone(),
-                    project_id.clone(),
-                    proxy_enabled,
-                    target_server.clone(),
-                    Arc::clone(&endpoint_manager),
-                )
+            Ok::<_, Infallible>(service_fn(move |mut req: Request<Body>| {
+                req.extensions_mut().insert(client);
+                serve(req, Arc::clone(&state))
             }))
         }
     });
@@ -1,65 +1,103 @@
 use hyper::{Body, Response};
 use log::{debug, error, info};
 use std::convert::Infallible;
-use tokio::time::sleep;
-use std::time::Duration;
 use itertools::Itertools;
+use bytes::Bytes;
+use crate::circuit::{CircuitBreaker, Permit};
+use crate::http_client::{self, Timeouts};
+use crate::latency::Delay;
+use crate::retry::{self, RetryPolicy};
+use crate::server::cookies::CookieRewrite;
+use crate::server::json_error;
+
+/// The target server unmocked requests are forwarded to.
+pub struct Upstream {
+    pub url: String,
+    pub retry: RetryPolicy,
+    pub circuit: CircuitBreaker,
+    pub client: reqwest::Client,
+    pub timeouts: Timeouts,
+    pub rewrite_location: bool,
+    pub cookies: CookieRewrite,
+}
+
+/// The parts of an incoming request that are forwarded to the target server.
+pub struct ProxiedRequest {
+    pub method: String,
+    pub uri: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Bytes,
+    /// Host header of the incoming request, used to point rewritten
+    /// `Location` headers back at the local listener.
+    pub host: Option<String>,
+}
 
 /// Handles proxying a request to a target server
 pub async fn proxy_request(
-    client: &reqwest::Client,
-    method_str: String,
-    uri_string: String,
-    server_url: String,
-    headers: Vec<(String, String)>,
-    body: impl Into<reqwest::Body>,
-    timeout: Option<u64>,
+    upstream: &Upstream,
+    request: ProxiedRequest,
+    permit: Permit<'_>,
+    delay: Option<Delay>,
 ) -> Result<Response<Body>, Infallible> {
-    let full_url = format!("{}{}", server_url.trim_end_matches('/'), uri_string);
+    let full_url = format!("{}{}", upstream.url.trim_end_matches('/'), request.uri);
     info!("Proxying request to target server: {}", full_url);
 
-    let mut proxy_req = client.request(
-        reqwest::Method::from_bytes(method_str.as_bytes()).unwrap(),
-        &full_url
-    );
+    let method = reqwest::Method::from_bytes(request.method.as_bytes()).unwrap();
+
+    // Set host header from target server URL
+    let host_value = url::Url::parse(&full_url).ok().and_then(|parsed_url| {
+        parsed_url.host_str().map(|host| match parsed_url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        })
+    });
+
+    let build_proxy_req = || {
+        let mut proxy_req = upstream.client.request(method.clone(), &full_url);
 
-    // Add original headers to proxy request
-    for (name, value) in headers {
-        if name.to_lowercase() != "host" {
-          proxy_req = proxy_req.header(&name, &value);
+        // Add original headers to proxy request
+        for (name, value) in &request.headers {
+            if name.to_lowercase() != "host" {
+              proxy_req = proxy_req.header(name, value);
+            }
         }
-    }
 
-    // Set host header from target server URL
-    if let Ok(parsed_url) = url::Url::parse(&full_url) {
-        if let Some(host) = parsed_url.host_str() {
-            let host_value = if let Some(port) = parsed_url.port() {
-                format!("{}:{}", host, port)
-            } else {
-                host.to_string()
-            };
-            proxy_req = proxy_req.header("Host", &host_value);
+        if let Some(host_value) = &host_value {
+            proxy_req = proxy_req.header("Host", host_value);
         }
-    }
 
-    debug!("proxy_req: {:?}", proxy_req);
+        debug!("proxy_req: {:?}", proxy_req);
+
+        proxy_req.body(request.body.clone())
+    };
+
+    if let Some(delay) = delay {
+        delay.before_call().await;
+    }
 
-    let body = body.into();
     // Send request to target server
-    match proxy_req.body(body).send().await {
+    match retry::send(&upstream.retry, &upstream.timeouts, &request.method, &full_url, build_proxy_req).await {
         Ok(proxy_resp) => {
             let proxy_status = proxy_resp.status();
+            // Application errors such as a plain 500 say nothing about the
+            // target server being reachable.
+            if is_unavailable(proxy_status) {
+                permit.failure();
+            } else {
+                permit.success();
+            }
             let proxy_headers = proxy_resp.headers().clone();
             
             
-            let proxy_body = match proxy_resp.bytes().await {
+            let proxy_body = match http_client::read_body(proxy_resp, &upstream.timeouts).await {
                 Ok(bytes) => bytes,
+                Err(e) if e.is_timeout() => {
+                    error!("Timed out reading target server response body: {}", e);
+                    return Ok(json_error(504, &format!("Timed out reading target server response: {}", e)));
+                }
                 Err(e) => {
                     error!("Error reading target server response body: {}", e);
-                    return Ok(Response::builder()
-                        .status(500)
-                        .body(Body::from("Failed to read proxy response body"))
-                        .unwrap());
+                    return Ok(json_error(500, "Failed to read target server response body"));
                 }
             };
             
@@ -78,6 +116,15 @@ pub async fn proxy_request(
             debug!("response headers: {:?}", proxy_headers);
             for (name, value) in &proxy_headers.into_iter().collect_vec() {
                 if let Some(name) = name {
+                    if name == "location" && upstream.rewrite_location {
+                        let rewritten = value.to_str().ok().zip(request.host.as_deref())
+                            .and_then(|(location, host)| rewrite_location(location, &upstream.url, host));
+                        if let Some(rewritten) = rewritten {
+                            debug!("rewriting location {:?} to {}", value, rewritten);
+                            response_builder = response_builder.header(name.as_str(), rewritten);
+                            continue;
+                        }
+                    }
                     if name != "set-cookie" {  // Skip set-cookie headers as we'll handle them separately
                       response_builder = response_builder.header(name.as_str(), value);
                     }
@@ -86,29 +133,66 @@ pub async fn proxy_request(
 
             // Add cookies
             for cookie in cookies {
+                let cookie = upstream.cookies.apply(cookie);
                 debug!("adding cookie header: {:?}", cookie);
                 response_builder = response_builder.header("set-cookie", cookie);
             }
 
             debug!("response headers after appending: {:?}", response_builder.headers_mut());
 
-            // Apply timeout if specified
-            if let Some(timeout) = timeout {
-                if timeout > 0 {
-                    info!("Sleeping for {}ms", timeout);
-                    sleep(Duration::from_millis(timeout)).await;
-                }
+            // Apply simulated latency if specified
+            if let Some(delay) = delay {
+                delay.after_response().await;
             }
 
             info!("Returning response from target server: {}", proxy_status);
             Ok(response_builder.body(Body::from(proxy_body)).unwrap())
         }
+        Err(e) if e.is_timeout() => {
+            permit.failure();
+            error!("Timed out contacting target server: {}", e);
+            Ok(json_error(504, &format!("Timed out contacting target server: {}", e)))
+        }
         Err(e) => {
+            permit.failure();
             error!("Failed to contact target server: {}", e);
-            Ok(Response::builder()
-                .status(502)
-                .body(Body::from(format!("Failed to contact target server: {}", e)))
-                .unwrap())
+            Ok(json_error(502, &format!("Failed to contact target server: {}", e)))
         }
     }
-} 
\ No newline at end of file
+}
+
+/// Statuses counted as target server failures by the circuit breaker.
+fn is_unavailable(status: hyper::StatusCode) -> bool {
+    matches!(status.as_u16(), 502..=504)
+}
+
+/// Points an absolute `Location` on the target server back at the local
+/// listener, stripping the target server's base path. Returns `None` for
+/// locations on other hosts and relative locations.
+fn rewrite_location(location: &str, upstream_url: &str, local_host: &str) -> Option<String> {
+    let location_url = url::Url::parse(location).ok()?;
+    let upstream_url = url::Url::parse(upstream_url).ok()?;
+
+    if location_url.origin() != upstream_url.origin() {
+        return None;
+    }
+
+    let base_path = upstream_url.path().trim_end_matches('/');
+    let path = location_url.path().strip_prefix(base_path).unwrap_or(location_url.path());
+
+    let mut rewritten = format!("http://{}/{}", local_host, path.trim_start_matches('/'));
+    if let Some(query) = location_url.query() {
+        rewritten.push('?');
+        rewritten.push_str(query);
+    }
+    if let Some(fragment) = location_url.fragment() {
+        rewritten.push('#');
+        rewritten.push_str(fragment);
+    }
+    Some(rewritten)
+}
+
+/// Response returned while the circuit for the target server is open.
+pub fn circuit_open_response() -> Response<Body> {
+    json_error(503, "Target server circuit is open")
+}
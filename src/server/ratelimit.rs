@@ -0,0 +1,245 @@
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use hyper::{Body, Response};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::cli::RateLimitArgs;
+use crate::pattern::{self, PathPattern};
+use crate::server::json_error;
+
+/// What requests are counted together.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LimitKey {
+    Global,
+    ClientIp,
+    Header(HeaderName),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+    /// At most `limit` requests per window, the count resets when a new
+    /// window starts.
+    FixedWindow,
+    /// Refills `limit` tokens per period up to `burst`, one token per request.
+    TokenBucket { burst: u32 },
+}
+
+/// A `--rate-limit` option:
+/// `<endpoint>=<count>/<period>[,by=ip|header:<name>][,bucket][,burst=<n>]`.
+#[derive(Clone, Debug)]
+pub struct RateLimitRule {
+    pub endpoint: PathPattern,
+    pub limit: u32,
+    pub period: Duration,
+    pub key: LimitKey,
+    pub algorithm: Algorithm,
+}
+
+fn parse_period(period: &str) -> Option<Duration> {
+    let period = period.trim();
+    let split = period.find(|c: char| !c.is_ascii_digit()).unwrap_or(period.len());
+    let (count, unit) = period.split_at(split);
+    let count: u64 = if count.is_empty() { 1 } else { count.parse().ok()? };
+    let seconds = match unit {
+        "s" | "sec" | "second" => 1,
+        "m" | "min" | "minute" => 60,
+        "h" | "hour" => 3600,
+        "d" | "day" => 86400,
+        _ => return None,
+    };
+    Some(Duration::from_secs(count * seconds)).filter(|d| !d.is_zero())
+}
+
+impl RateLimitRule {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut parts = spec.split(',');
+        let (endpoint, rate) = parts
+            .next()
+            .and_then(|first| first.split_once('='))
+            .ok_or_else(|| format!("Invalid rate limit {:?}, expected <endpoint>=<count>/<period>", spec))?;
+
+        let (limit, period) = rate
+            .split_once('/')
+            .and_then(|(limit, period)| Some((limit.trim().parse::<u32>().ok()?, parse_period(period)?)))
+            .filter(|(limit, _)| *limit > 0)
+            .ok_or_else(|| format!("Invalid rate {:?} in {:?}, expected e.g. 10/s, 100/min or 1000/15m", rate, spec))?;
+
+        let mut rule = Self {
+            endpoint: PathPattern::parse(endpoint.trim()),
+            limit,
+            period,
+            key: LimitKey::Global,
+            algorithm: Algorithm::FixedWindow,
+        };
+        let mut burst = None;
+
+        for option in parts {
+            match option.trim().split_once('=') {
+                Some(("by", "global")) => rule.key = LimitKey::Global,
+                Some(("by", "ip")) => rule.key = LimitKey::ClientIp,
+                Some(("by", key)) if key.starts_with("header:") => {
+                    let name = HeaderName::from_bytes(key["header:".len()..].trim().as_bytes())
+                        .map_err(|_| format!("Invalid header name in {:?}", spec))?;
+                    rule.key = LimitKey::Header(name);
+                }
+                Some(("burst", value)) => {
+                    burst = Some(value.trim().parse::<u32>().ok().filter(|b| *b > 0)
+                        .ok_or_else(|| format!("Invalid burst {:?} in {:?}", value, spec))?);
+                }
+                None if option.trim() == "bucket" => rule.algorithm = Algorithm::TokenBucket { burst: limit },
+                _ => return Err(format!("Invalid rate limit option {:?} in {:?}", option, spec)),
+            }
+        }
+
+        if let Some(burst) = burst {
+            rule.algorithm = Algorithm::TokenBucket { burst };
+        }
+        Ok(rule)
+    }
+
+    /// Largest number of requests allowed in a row.
+    fn capacity(&self) -> u32 {
+        match self.algorithm {
+            Algorithm::FixedWindow => self.limit,
+            Algorithm::TokenBucket { burst } => burst,
+        }
+    }
+}
+
+/// Usage of one rule by one client.
+enum Usage {
+    Window { started: Instant, count: u32 },
+    Bucket { tokens: f64, updated: Instant },
+}
+
+/// The outcome of a rate limit check.
+pub struct Decision {
+    allowed: bool,
+    limit: u32,
+    remaining: u32,
+    /// Until the limit is fully available again.
+    reset: Duration,
+    /// Until the next request would be allowed.
+    retry_after: Duration,
+}
+
+fn ceil_secs(duration: Duration) -> u64 {
+    duration.as_millis().div_ceil(1000) as u64
+}
+
+impl Decision {
+    /// Adds the `X-RateLimit-*` headers describing this decision.
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        headers.insert("x-ratelimit-limit", HeaderValue::from(self.limit));
+        headers.insert("x-ratelimit-remaining", HeaderValue::from(self.remaining));
+        headers.insert("x-ratelimit-reset", HeaderValue::from(ceil_secs(self.reset)));
+        if !self.allowed {
+            headers.insert(hyper::header::RETRY_AFTER, HeaderValue::from(ceil_secs(self.retry_after).max(1)));
+        }
+    }
+
+    /// The 429 response for a rejected request, or `None` if it is allowed.
+    pub fn rejection(&self) -> Option<Response<Body>> {
+        if self.allowed {
+            return None;
+        }
+        let mut response = json_error(429, "Rate limit exceeded");
+        self.apply(response.headers_mut());
+        Some(response)
+    }
+}
+
+/// Rate limits applied before requests reach the remote or target server.
+#[derive(Default)]
+pub struct RateLimiter {
+    rules: Vec<RateLimitRule>,
+    usage: Mutex<HashMap<(usize, String), Usage>>,
+}
+
+impl RateLimiter {
+    pub fn from_args(args: &RateLimitArgs) -> Result<Self, String> {
+        let rules = args
+            .rate_limit
+            .iter()
+            .map(|spec| RateLimitRule::parse(spec))
+            .collect::<Result<Vec<_>, _>>()?;
+        for rule in &rules {
+            if rule.endpoint.ends_with_star() {
+                warn!("Rate limit pattern {} matches a single segment, use ** to match a prefix", rule.endpoint);
+            }
+            info!("Rate limiting {} to {}/{}s by {:?}", rule.endpoint, rule.limit, rule.period.as_secs(), rule.key);
+        }
+
+        Ok(Self {
+            rules,
+            usage: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Counts a request to `path` against the first matching rule. Returns
+    /// `None` if no rule applies.
+    pub fn check(&self, path: &str, client_ip: Option<String>, headers: &HeaderMap) -> Option<Decision> {
+        let path = pattern::normalize(path);
+        let (index, rule) = self.rules
+            .iter()
+            .enumerate()
+            .find(|(_, rule)| rule.endpoint.matches(path))?;
+
+        let key = match &rule.key {
+            LimitKey::Global => String::new(),
+            LimitKey::ClientIp => client_ip.unwrap_or_default(),
+            LimitKey::Header(name) => headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+                .to_string(),
+        };
+
+        let now = Instant::now();
+        let mut usage = self.usage.lock().unwrap();
+        let usage = usage.entry((index, key)).or_insert_with(|| match rule.algorithm {
+            Algorithm::FixedWindow => Usage::Window { started: now, count: 0 },
+            Algorithm::TokenBucket { burst } => Usage::Bucket { tokens: burst as f64, updated: now },
+        });
+
+        Some(match usage {
+            Usage::Window { started, count } => {
+                if now.duration_since(*started) >= rule.period {
+                    *started = now;
+                    *count = 0;
+                }
+                let allowed = *count < rule.limit;
+                if allowed {
+                    *count += 1;
+                }
+                let reset = rule.period.saturating_sub(now.duration_since(*started));
+                Decision {
+                    allowed,
+                    limit: rule.limit,
+                    remaining: rule.limit - *count,
+                    reset,
+                    retry_after: reset,
+                }
+            }
+            Usage::Bucket { tokens, updated } => {
+                let per_second = rule.limit as f64 / rule.period.as_secs_f64();
+                let capacity = rule.capacity() as f64;
+                *tokens = (*tokens + now.duration_since(*updated).as_secs_f64() * per_second).min(capacity);
+                *updated = now;
+
+                let allowed = *tokens >= 1.0;
+                if allowed {
+                    *tokens -= 1.0;
+                }
+                Decision {
+                    allowed,
+                    limit: rule.capacity(),
+                    remaining: tokens.floor() as u32,
+                    reset: Duration::from_secs_f64((capacity - *tokens) / per_second),
+                    retry_after: Duration::from_secs_f64((1.0 - *tokens).max(0.0) / per_second),
+                }
+            }
+        })
+    }
+}
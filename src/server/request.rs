@@ -3,44 +3,81 @@ use hyper::{Body, Request, Response};
 use std::convert::Infallible;
 use hyper::body::HttpBody;
 use bytes::Buf;
-use crate::ping::EndpointManager;
 use std::sync::Arc;
-use tokio::time::sleep;
-use std::time::Duration;
-use crate::server::proxy::proxy_request;
+use std::time::Instant;
+use crate::http_client;
+use crate::latency::Delay;
+use crate::retry;
+use crate::server::{json_error, ClientAddr, Project, ServerState};
+use crate::server::proxy::{circuit_open_response, proxy_request, ProxiedRequest};
 
 /// Handles an individual incoming HTTP request.
 pub async fn handle(
     req: Request<Body>,
-    remote_base: String,
-    project_id: String,
-    proxy_enabled: bool,
-    target_server: Option<String>,
-    endpoint_manager: Arc<EndpointManager>,
+    state: Arc<ServerState>,
+    project: Arc<Project>,
 ) -> Result<Response<Body>, Infallible> {
+    // Rate limits are enforced before anything is sent to the remote or
+    // target server.
+    let path = req.uri().path().trim_start_matches('/').to_string();
+    let client_ip = req.extensions().get::<ClientAddr>().map(|client| client.0.ip().to_string());
+    let rate_limit = state.rate_limiter.check(&path, client_ip, req.headers());
+    if let Some(rejection) = rate_limit.as_ref().and_then(|decision| decision.rejection()) {
+        info!("Rate limit exceeded for {}", path);
+        return Ok(rejection);
+    }
+
+    let mut response = handle_endpoint(req, state, project).await?;
+    if let Some(decision) = rate_limit {
+        decision.apply(response.headers_mut());
+    }
+    Ok(response)
+}
+
+/// Mocks or proxies a request to a project endpoint.
+async fn handle_endpoint(
+    req: Request<Body>,
+    state: Arc<ServerState>,
+    project: Arc<Project>,
+) -> Result<Response<Body>, Infallible> {
+    let started = Instant::now();
+
     // First get copies/clones of everything we need
     let method_str = req.method().to_string();
-    let uri_string = req.uri().to_string();
     let request_url = req.uri().path_and_query()
         .map(|p| p.as_str())
         .unwrap_or("")
         .to_string();
+    // Absolute-form URIs (forward-proxy mode) are reduced to their path.
+    let uri_string = request_url.clone();
     let headers = req.headers().clone();
     
     // Get path without leading slash for endpoint lookup
     let path = request_url.trim_start_matches('/').to_string();
 
-    let endpoint_info: Option<(u64, bool)> = endpoint_manager.get_endpoint_info(&path).await;
+    let endpoint_info = project.endpoint_manager.get_endpoint_info(&method_str, &path).await;
+    let mut should_proxy_endpoint = endpoint_info.as_ref().is_some_and(|(_, proxied)| *proxied);
+    if !should_proxy_endpoint && project.upstream.is_some() && project.endpoint_manager.offline_fallback() {
+        debug!("API Mimic is unreachable, proxying {} to the target server", path);
+        should_proxy_endpoint = true;
+    }
+    let latency = state.latency.resolve(&method_str, &path, endpoint_info.map(|(latency, _)| latency));
+    if let Some(latency) = &latency {
+        debug!("Latency profile for {}: {}", path, latency);
+    }
+    // Sampled once so the mocked and proxied paths agree on the delay.
+    let delay = latency.map(|latency| Delay {
+        mode: state.latency.mode,
+        duration: latency.sample(),
+        started,
+    });
 
     // Now we can safely consume the request
     let whole_body = match req.collect().await {
         Ok(body) => body.aggregate(),
         Err(e) => {
             error!("Failed to collect body: {}", e);
-            return Ok(Response::builder()
-                .status(500)
-                .body(Body::from(format!("Apimimic: Failed to collect body: {}", e)))
-                .unwrap());
+            return Ok(json_error(500, &format!("Failed to collect body: {}", e)));
         }
     };
 
@@ -53,16 +90,15 @@ pub async fn handle(
                 serde_json::Value::Object(serde_json::Map::new())
             } else {
                 error!("Failed to parse JSON: {}", e);
-                let error_json = serde_json::json!({"message": format!("Apimimic: Invalid JSON: {}", e)}).to_string();
-                return Ok(Response::builder()
-                    .status(500)
-                    .header("Content-Type", "application/json")
-                    .body(Body::from(error_json))
-                    .unwrap());
+                return Ok(json_error(500, &format!("Invalid JSON: {}", e)));
             }
         }
     };
 
+    let host = headers.get(hyper::header::HOST)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string());
+
     // Convert headers to Vec after cloning
     let headers: Vec<(String, String)> = headers
     .iter()
@@ -77,20 +113,29 @@ pub async fn handle(
     let json = serde_json::to_string(&data).unwrap();
 
     // Check endpoint configuration
-    if let Some((timeout, should_proxy)) = endpoint_info {
+    if should_proxy_endpoint {
 
         // If endpoint should be proxied and we have a target server
-        if should_proxy && target_server.is_some() {
-            debug!("Proxying request to {} with {}ms timeout", path, timeout);
-            return proxy_request(
-                &reqwest::Client::new(),
-                method_str,
-                uri_string,
-                target_server.unwrap(),
-                headers,
-                json,
-                Some(timeout),
-            ).await;
+        if let Some(upstream) = &project.upstream {
+            if let Some(permit) = upstream.circuit.try_acquire() {
+                debug!("Proxying request to {} with {:?} delay", path, delay.map(|d| d.duration));
+                return proxy_request(
+                    upstream,
+                    ProxiedRequest {
+                        method: method_str,
+                        uri: uri_string,
+                        headers,
+                        body: json.into(),
+                        host,
+                    },
+                    permit,
+                    delay,
+                ).await;
+            }
+            if !upstream.circuit.fallback_to_mock {
+                return Ok(circuit_open_response());
+            }
+            info!("Target server circuit is open, serving {} from API Mimic", path);
         }
     }
 
@@ -104,75 +149,84 @@ pub async fn handle(
 
     debug!("Payload: {}", payload_to_send);
 
-    // Create reqwest client
-    let client = reqwest::Client::new();
-    
     // Build request to API Mimic
-    let mut mimic_req = client.post(&remote_base)
-        .header("apimimic-project-id", &project_id)
-        .header("Content-Type", "Application/json")
-        .header("Content-Length", payload_to_send.to_string().len().to_string());
-
-    if proxy_enabled && target_server.is_some() {
-        if let Some(target) = &target_server {
-            mimic_req = mimic_req.header("apimimic-cli-proxy", target);
+    let build_mimic_req = || {
+        let mut mimic_req = state.remote_client.post(&project.remote_base)
+            .header("apimimic-project-id", &project.project_id)
+            .header("Content-Type", "Application/json")
+            .header("Content-Length", payload_to_send.to_string().len().to_string());
+
+        if let Some(upstream) = &project.upstream {
+            mimic_req = mimic_req.header("apimimic-cli-proxy", &upstream.url);
         }
-    }
 
-    // Add original headers
-    for (name, value) in &headers {
-        mimic_req = mimic_req.header(name, value);
-    }
+        // Add original headers
+        for (name, value) in &headers {
+            mimic_req = mimic_req.header(name, value);
+        }
 
-    info!("Sending request to API Mimic: {} {}", remote_base, request_url.trim_start_matches('/').to_string());
+        mimic_req.json(&payload_to_send)
+    };
+
+    info!("Sending request to API Mimic: {} {}", project.remote_base, request_url.trim_start_matches('/'));
 
     debug!("Payload: {}", payload_to_send);
 
+    if let Some(delay) = delay {
+        delay.before_call().await;
+    }
+
     // Send request to API Mimic
-    let mimic_resp = match mimic_req.json(&payload_to_send).send().await {
+    let mimic_resp = match retry::send(&state.remote_retry, &state.remote_timeouts, &method_str, &project.remote_base, build_mimic_req).await {
         Ok(resp) => resp,
+        Err(e) if e.is_timeout() => {
+            error!("Timed out contacting remote server: {}", e);
+            return Ok(json_error(504, &format!("Timed out contacting remote server: {}", e)));
+        }
         Err(e) => {
             error!("Failed to contact remote server: {}", e);
-            let error_json = serde_json::json!({"message": format!("Apimimic: Failed to contact remote server: {}", e)}).to_string();
-            return Ok(Response::builder()
-                .status(500)
-                .header("Content-Type", "application/json")
-                .body(Body::from(error_json))
-                .unwrap());
+            return Ok(json_error(500, &format!("Failed to contact remote server: {}", e)));
         }
     };
 
     let status = mimic_resp.status();
     let mimic_headers = mimic_resp.headers().clone();
-    let mimic_body = match mimic_resp.bytes().await {
+    let mimic_body = match http_client::read_body(mimic_resp, &state.remote_timeouts).await {
         Ok(bytes) => bytes,
+        Err(e) if e.is_timeout() => {
+            error!("Timed out reading remote response body: {}", e);
+            return Ok(json_error(504, "Timed out reading remote response body"));
+        }
         Err(e) => {
             error!("Error reading remote response body: {}", e);
-            let error_json = serde_json::json!({"message": "Apimimic: Failed to read remote response body"}).to_string();
-            return Ok(Response::builder()
-                .status(500)
-                .header("Content-Type", "application/json")
-                .body(Body::from(error_json))
-                .unwrap());
+            return Ok(json_error(500, "Failed to read remote response body"));
         }
     };
 
     // Check if we need to proxy
-    let should_proxy = proxy_enabled 
-        && target_server.is_some() 
+    let should_proxy = project.upstream.is_some() 
         && mimic_headers.get("apimimic-proxy-request").is_some();
 
     if should_proxy {
-        if let Some(server_url) = target_server {
-            return proxy_request(
-                &client,
-                method_str,
-                uri_string,
-                server_url,
-                headers,
-                json,
-                endpoint_manager.get_endpoint_info(&path).await.map(|(t, _)| t),
-            ).await;
+        if let Some(upstream) = &project.upstream {
+            if let Some(permit) = upstream.circuit.try_acquire() {
+                return proxy_request(
+                    upstream,
+                    ProxiedRequest {
+                        method: method_str,
+                        uri: uri_string,
+                        headers,
+                        body: json.into(),
+                        host,
+                    },
+                    permit,
+                    delay,
+                ).await;
+            }
+            if !upstream.circuit.fallback_to_mock {
+                return Ok(circuit_open_response());
+            }
+            info!("Target server circuit is open, returning API Mimic response for {}", path);
         }
     }
 
@@ -190,12 +244,9 @@ pub async fn handle(
     }
 
     // Timout
-    if let Some((timeout, _should_proxy)) = endpoint_manager.get_endpoint_info(&path).await {
+    if let Some(delay) = delay {
         // Apply timeout regardless of proxy status
-        if timeout > 0 {
-            info!("Sleeping for {}ms", timeout);
-            sleep(Duration::from_millis(timeout)).await;
-        }
+        delay.after_response().await;
     }
 
     info!("Returning response from API Mimic: {}", status);
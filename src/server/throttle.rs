@@ -0,0 +1,109 @@
+use hyper::body::HttpBody;
+use hyper::Body;
+use log::debug;
+use std::time::Duration;
+use tokio::time::sleep;
+use crate::cli::BandwidthArgs;
+use crate::pattern::RouteMap;
+
+/// Named network conditions accepted in place of a rate, in bytes per second.
+const PRESETS: &[(&str, u64)] = &[
+    ("2g", 250_000 / 8),
+    ("slow-3g", 400_000 / 8),
+    ("3g", 1_600_000 / 8),
+    ("4g", 9_000_000 / 8),
+];
+
+/// How often paced bytes are released.
+const TICK: Duration = Duration::from_millis(50);
+
+/// A transfer rate in bytes per second.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bandwidth(u64);
+
+impl Bandwidth {
+    /// Parses a preset (`3g`), a bit rate (`256kbps`, `2mbps`) or a byte rate
+    /// (`50kb/s`, `1mb/s`, or a plain number of bytes per second).
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let spec = spec.trim().to_lowercase();
+        if let Some((_, rate)) = PRESETS.iter().find(|(name, _)| *name == spec) {
+            return Ok(Self(*rate));
+        }
+
+        let units: &[(&str, f64)] = &[
+            ("kbps", 1000.0 / 8.0),
+            ("mbps", 1_000_000.0 / 8.0),
+            ("bps", 1.0 / 8.0),
+            ("kb/s", 1000.0),
+            ("mb/s", 1_000_000.0),
+            ("b/s", 1.0),
+        ];
+        let (number, factor) = units
+            .iter()
+            .find_map(|(unit, factor)| spec.strip_suffix(unit).map(|number| (number, *factor)))
+            .unwrap_or((spec.as_str(), 1.0));
+
+        match number.trim().parse::<f64>() {
+            Ok(value) if value.is_finite() && value * factor >= 1.0 => Ok(Self((value * factor) as u64)),
+            _ => Err(format!("Invalid bandwidth {:?}, expected e.g. 3g, 256kbps or 50kb/s", spec)),
+        }
+    }
+
+    /// Wraps `body` so its bytes are released at this rate.
+    pub fn pace(self, mut body: Body) -> Body {
+        let (mut sender, paced) = Body::channel();
+        let per_tick = (self.0 as u128 * TICK.as_millis() / 1000).max(1) as usize;
+
+        tokio::spawn(async move {
+            while let Some(chunk) = body.data().await {
+                let mut chunk = match chunk {
+                    Ok(chunk) => chunk,
+                    Err(e) => {
+                        debug!("Throttled body failed: {}", e);
+                        sender.abort();
+                        return;
+                    }
+                };
+                while !chunk.is_empty() {
+                    let piece = chunk.split_to(per_tick.min(chunk.len()));
+                    let wait = TICK.mul_f64(piece.len() as f64 / per_tick as f64);
+                    if sender.send_data(piece).await.is_err() {
+                        return;
+                    }
+                    sleep(wait).await;
+                }
+            }
+        });
+
+        paced
+    }
+}
+
+/// Bandwidth limits set on the command line.
+#[derive(Default)]
+pub struct Throttle {
+    default: Option<Bandwidth>,
+    endpoints: RouteMap<Bandwidth>,
+}
+
+impl Throttle {
+    pub fn from_args(args: &BandwidthArgs) -> Result<Self, String> {
+        let default = args.bandwidth.as_deref().map(Bandwidth::parse).transpose()?;
+
+        let mut endpoints = RouteMap::default();
+        for spec in &args.endpoint_bandwidth {
+            let (path, rate) = spec
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid endpoint bandwidth {:?}, expected <path>=<rate>", spec))?;
+            endpoints.insert(path, Bandwidth::parse(rate)?);
+        }
+
+        Ok(Self { default, endpoints })
+    }
+
+    /// Limit for a request: the most specific endpoint limit, then the
+    /// global one.
+    pub fn resolve(&self, method: &str, path: &str) -> Option<Bandwidth> {
+        self.endpoints.get(method, path).copied().or(self.default)
+    }
+}
@@ -0,0 +1,105 @@
+use hyper::http::uri::Authority;
+use hyper::{Body, Request, Uri};
+use std::sync::Arc;
+use crate::server::Project;
+use crate::utils::parse_server_url;
+
+/// Which requests a virtual host serves.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Matcher {
+    /// Requests whose Host header (port ignored) equals the name.
+    Host(String),
+    /// Requests whose path starts with the prefix; the prefix is stripped.
+    PathPrefix(String),
+}
+
+/// A `--vhost` option: `<host or /prefix>=<project>[,remote=<url>][,server=<url>]`.
+#[derive(Clone, Debug)]
+pub struct VirtualHostSpec {
+    pub matcher: Matcher,
+    pub project: String,
+    pub remote: Option<String>,
+    pub server: Option<String>,
+}
+
+impl VirtualHostSpec {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut parts = spec.split(',');
+        let (target, project) = parts
+            .next()
+            .and_then(|first| first.split_once('='))
+            .ok_or_else(|| format!("Invalid vhost {:?}, expected <host or /prefix>=<project>", spec))?;
+
+        let target = target.trim();
+        let matcher = if target.starts_with('/') {
+            Matcher::PathPrefix(format!("/{}", target.trim_matches('/')))
+        } else {
+            Matcher::Host(target.to_lowercase())
+        };
+
+        let mut parsed = Self {
+            matcher,
+            project: project.trim().to_string(),
+            remote: None,
+            server: None,
+        };
+        if parsed.project.is_empty() {
+            return Err(format!("Invalid vhost {:?}, missing project key", spec));
+        }
+
+        for option in parts {
+            match option.split_once('=') {
+                Some(("remote", url)) => parsed.remote = Some(url.trim().to_string()),
+                Some(("server", url)) => parsed.server = parse_server_url(&Some(url.trim().to_string())),
+                _ => return Err(format!("Invalid vhost option {:?} in {:?}", option, spec)),
+            }
+        }
+
+        Ok(parsed)
+    }
+}
+
+/// Picks the project serving `req`. For path prefix matches the request URI
+/// is rewritten without the prefix.
+pub fn route(
+    virtual_hosts: &[(Matcher, Arc<Project>)],
+    default: Option<&Arc<Project>>,
+    mut req: Request<Body>,
+) -> (Option<Arc<Project>>, Request<Body>) {
+    let host = req
+        .uri()
+        .host()
+        .map(|h| h.to_string())
+        .or_else(|| {
+            req.headers()
+                .get(hyper::header::HOST)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<Authority>().ok())
+                .map(|authority| authority.host().to_string())
+        })
+        .unwrap_or_default()
+        .to_lowercase();
+
+    for (matcher, project) in virtual_hosts {
+        match matcher {
+            Matcher::Host(name) if *name == host => return (Some(Arc::clone(project)), req),
+            Matcher::PathPrefix(prefix) => {
+                let path = req.uri().path();
+                let rest = match path.strip_prefix(prefix.as_str()) {
+                    Some(rest) if rest.is_empty() || rest.starts_with('/') => rest,
+                    _ => continue,
+                };
+
+                let query = req.uri().query().map(|q| format!("?{}", q)).unwrap_or_default();
+                let rewritten = format!("/{}{}", rest.trim_start_matches('/'), query);
+                if let Ok(uri) = rewritten.parse::<Uri>() {
+                    *req.uri_mut() = uri;
+                }
+                return (Some(Arc::clone(project)), req);
+            }
+            _ => {}
+        }
+    }
+
+    (default.cloned(), req)
+}
@@ -0,0 +1,98 @@
+/// One server-sent event.
+#[derive(Debug, PartialEq)]
+pub struct Event {
+    /// The `event:` field, `message` if the event has none.
+    pub name: String,
+    pub data: String,
+}
+
+/// Splits a `text/event-stream` body into events as chunks arrive.
+#[derive(Default)]
+pub struct Parser {
+    buffer: Vec<u8>,
+}
+
+impl Parser {
+    /// Adds a chunk of the stream and returns the events it completed.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<Event> {
+        self.buffer.extend(chunk.iter().filter(|b| **b != b'\r'));
+
+        let mut events = Vec::new();
+        while let Some(end) = self.buffer.windows(2).position(|w| w == b"\n\n") {
+            let block: Vec<u8> = self.buffer.drain(..end + 2).collect();
+            if let Some(event) = parse_block(&String::from_utf8_lossy(&block)) {
+                events.push(event);
+            }
+        }
+        events
+    }
+}
+
+fn parse_block(block: &str) -> Option<Event> {
+    let mut name = None;
+    let mut data: Vec<&str> = Vec::new();
+
+    for line in block.lines() {
+        // Lines starting with a colon are comments, used as keep-alives.
+        if line.is_empty() || line.starts_with(':') {
+            continue;
+        }
+        let (field, value) = line.split_once(':').unwrap_or((line, ""));
+        let value = value.strip_prefix(' ').unwrap_or(value);
+        match field {
+            "event" => name = Some(value.to_string()),
+            "data" => data.push(value),
+            _ => {}
+        }
+    }
+
+    if name.is_none() && data.is_empty() {
+        return None;
+    }
+    Some(Event {
+        name: name.unwrap_or_else(|| "message".to_string()),
+        data: data.join("\n"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(name: &str, data: &str) -> Event {
+        Event { name: name.to_string(), data: data.to_string() }
+    }
+
+    #[test]
+    fn events_split_across_chunks() {
+        let mut parser = Parser::default();
+        assert_eq!(parser.push(b"event: upd"), vec![]);
+        assert_eq!(parser.push(b"ate\ndata: {\"a\""), vec![]);
+        assert_eq!(parser.push(b": 1}\n"), vec![]);
+        assert_eq!(parser.push(b"\nevent: snap"), vec![event("update", "{\"a\": 1}")]);
+        assert_eq!(parser.push(b"shot\ndata: {}\n\n"), vec![event("snapshot", "{}")]);
+    }
+
+    #[test]
+    fn crlf_line_endings() {
+        let mut parser = Parser::default();
+        let events = parser.push(b"event: update\r\ndata: x\r\n\r\ndata: y\r\n\r");
+        assert_eq!(events, vec![event("update", "x")]);
+        assert_eq!(parser.push(b"\n"), vec![event("message", "y")]);
+    }
+
+    #[test]
+    fn comments_are_ignored() {
+        let mut parser = Parser::default();
+        assert_eq!(parser.push(b": keep-alive\n\n"), vec![]);
+        let events = parser.push(b": keep-alive\nevent: update\n: between\ndata: x\n\n");
+        assert_eq!(events, vec![event("update", "x")]);
+    }
+
+    #[test]
+    fn multi_line_data() {
+        let mut parser = Parser::default();
+        let events = parser.push(b"event: snapshot\ndata: {\"a\":\ndata:1}\ndata\n\n");
+        assert_eq!(events, vec![event("snapshot", "{\"a\":\n1}\n")]);
+    }
+}
@@ -19,4 +19,4 @@ pub fn parse_server_url(server: &Option<String>) -> Option<String> {
             s.to_string()
         }
     })
-} 
\ No newline at end of file
+} 